@@ -1,82 +1,933 @@
+use std::fmt;
 use std::fs;
+use std::io::{ self, BufRead, Read, Write };
+use std::path::{ Path, PathBuf };
 use std::process;
+use std::sync::{ Arc, Mutex, OnceLock };
 use std::str;
 use std::time::{ Duration, Instant, UNIX_EPOCH };
-use std::collections::{ HashMap };
+use std::collections::{ BTreeMap, BTreeSet, HashMap, HashSet };
 
 use serde::{ Deserialize, Serialize };
 
-use console::{ style, Emoji };
+use console::{ style, Emoji, Term };
 
-use indicatif::{ ProgressBar, ProgressStyle, HumanDuration };
+use indicatif::{ MultiProgress, ProgressBar, ProgressStyle, HumanDuration };
 
-use clap::Parser;
+use clap::{ Parser, Subcommand };
 
-static GREEN_TICK: Emoji<'_, '_> = Emoji("✅", "");
-static RED_CROSS: Emoji<'_, '_> = Emoji("❌", "");
+use sha2::{ Digest, Sha256 };
 
-#[derive(Serialize, Deserialize)]
+use similar::TextDiff;
+
+use regex::Regex;
+
+static GREEN_TICK: Emoji<'_, '_> = Emoji("✅", "[+]");
+static RED_CROSS: Emoji<'_, '_> = Emoji("❌", "[x]");
+static STOP_SIGN: Emoji<'_, '_> = Emoji("🛑", "[-]");
+
+// container runtimes that are looked for on PATH, in order of preference
+static CONTAINER_RUNTIMES: [&str; 2] = ["docker", "podman"];
+
+// marks (and separates the items of) list variables, which are stored as
+// strings like every other variable
+const LIST_SEPARATOR: char = '\u{1f}';
+
+// extensions of the build files coyote looks for, in order of preference
+static CONFIG_EXTENSIONS: [&str; 4] = ["json", "yaml", "yml", "toml"];
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone)]
 struct Command {
+    // annotations for people reading the config, which coyote ignores
+    #[serde(rename = "_comment")]
+    comment: Option<serde_json::Value>,
+    description: Option<String>,
+
     command: String,
     arguments: Vec<String>,
-    run_if: Option<Vec<String>>
+    run_if: Option<Vec<ConditionToken>>,
+
+    // programs to try in order if `command` cannot be found on PATH
+    fallback: Option<Vec<String>>,
+
+    // expression over variables (eg. `{BUILD_TYPE} == release`) that must
+    // be true for the command to run
+    #[serde(rename = "if")]
+    if_expression: Option<String>,
+
+    // exit code and stdout marker that ask coyote to stop the build early
+    // without treating it as an error
+    stop_code: Option<i32>,
+    stop_marker: Option<String>,
+
+    // expected stdout of the command, and where to write the actual output
+    // if it does not match
+    golden: Option<String>,
+    golden_actual: Option<String>,
+
+    // variable to store the exit status of the command in, for use by later
+    // commands in the same target
+    capture_status: Option<String>,
+
+    // variable to store the (trimmed) stdout of the command in, for use by
+    // later commands in the same target
+    capture: Option<String>,
+
+    // set to false to run the command directly, even if there is a top level
+    // `shell`
+    shell: Option<bool>,
+
+    // runs the command without a spinner
+    quiet: Option<bool>,
+
+    // marks commands that cannot run in `--offline` mode
+    requires_network: Option<bool>,
+
+    // content piped into the command's stdin, either written inline (with
+    // variable and backtick substitution) or read from a file
+    stdin: Option<String>,
+    stdin_file: Option<String>,
+
+    // directory to run the command in, relative to the one coyote runs in
+    cwd: Option<String>,
+
+    // environment variables for this command only, overriding the top level
+    // `env`
+    env: Option<BTreeMap<String, String>>,
+
+    // kills the command if it runs for longer than this many seconds
+    timeout: Option<u64>,
+
+    // prints the command's output as it runs, rather than only showing it
+    // when the command fails
+    stream: Option<bool>,
+
+    // regexes for the streamed lines to show, and the ones to leave out. The
+    // full output is still captured
+    output_filter: Option<String>,
+    output_exclude: Option<String>,
+
+    // sends stderr into the same pipe as stdout, so that output from tools
+    // that interleave the two keeps its order
+    merge_stderr: Option<bool>,
+
+    // scheduling priority of the spawned process (Unix only), overriding the
+    // global `nice`
+    nice: Option<i32>,
+
+    // retries the command if it fails, overriding the target's `retry`
+    retry: Option<RetryPolicy>,
+
+    // image to run the command in, overriding the target's `container`
+    container: Option<String>,
+
+    // files the command generates, and whether it should only run when any
+    // of them are missing (rather than by its `run_if`)
+    outputs: Option<Vec<String>>,
+    only_outputs_missing: Option<bool>,
+
+    // fails the command (so that it can be retried) if it exits successfully
+    // without writing all of its outputs
+    require_outputs: Option<bool>
 }
 
-#[derive(Serialize, Deserialize)]
+/// A word of a `run_if` condition, or one of the conditions that an `all` or
+/// `any` condition combines
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum ConditionToken {
+    Word(String),
+    Condition(Vec<ConditionToken>)
+}
+
+/// How the delay between retries of a failing command grows
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Backoff {
+    /// Waits `delay_ms` before every retry
+    Fixed,
+
+    /// Waits `delay_ms` multiplied by the retry number
+    Linear,
+
+    /// Doubles the delay after every retry, starting from `delay_ms`
+    Exponential
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone)]
+struct RetryPolicy {
+    count: u32,
+    delay_ms: Option<u64>,
+    backoff: Option<Backoff>
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone)]
 struct Executable {
+    #[serde(rename = "_comment")]
+    comment: Option<serde_json::Value>,
+    description: Option<String>,
+
     target: String,
-    commands: Vec<Command>
+    commands: Vec<Command>,
+
+    // expression over variables that must be true for the target to build
+    #[serde(rename = "if")]
+    if_expression: Option<String>,
+
+    // when set, only this target is built by default
+    entry: Option<bool>,
+
+    // target whose commands run before this target's own commands
+    extends: Option<String>,
+
+    // targets that have to be built before this one
+    depends: Option<Vec<String>>,
+
+    // commands that only run if one of the target's commands fails
+    on_failure: Option<Vec<Command>>,
+
+    // retry policy for every command in the target that does not set its
+    // own
+    retry: Option<RetryPolicy>,
+
+    // image to run every command of the target in
+    container: Option<String>,
+
+    // variables that extend (or override) the global ones for this target
+    variables: Option<serde_json::Value>,
+
+    // runs all of the target's commands at the same time
+    parallel: Option<bool>,
+
+    // global and target variables after preprocessing
+    #[serde(skip)]
+    resolved_variables: HashMap<String, String>
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize)]
 struct CoyoteJson {
+    #[serde(rename = "_comment")]
+    comment: Option<serde_json::Value>,
+    description: Option<String>,
+
     project_name: String,
     variables: serde_json::Value,
-    executables: Vec<Executable>
+    executables: Vec<Executable>,
+
+    // exit codes that indicate a transient failure (eg. a network hiccup),
+    // and how many times a command failing with one of them is retried
+    transient_exit_codes: Option<Vec<i32>>,
+    transient_retries: Option<u32>,
+
+    // scheduling priority for every spawned process (Unix only)
+    nice: Option<i32>,
+
+    // environment variables set for every command, on top of the ones coyote
+    // inherits
+    env: Option<BTreeMap<String, String>>,
+
+    // tools that must be on PATH (optionally with a version constraint such
+    // as `gcc>=9`) before anything is built
+    requires: Option<Vec<String>>,
+
+    // header of `#define`s written from variables before anything is built
+    generate_header: Option<HeaderConfig>,
+
+    // program used to run containerized commands (eg. `docker` or `podman`),
+    // detected from PATH if not set
+    container_runtime: Option<String>,
+
+    // shell that every command is run through (eg. `sh -c`), with the whole
+    // command line as its last argument
+    shell: Option<String>,
+
+    // other build files (relative to this one) whose executables and
+    // variables are merged into this one
+    include: Option<Vec<String>>,
+
+    // variables after preprocessing
+    #[serde(skip)]
+    resolved_variables: HashMap<String, String>,
+
+    // `env` after preprocessing
+    #[serde(skip)]
+    resolved_env: BTreeMap<String, String>,
+
+    // every build file merged in by `include`, for `--watch`
+    #[serde(skip)]
+    included: Vec<String>
+}
+
+#[derive(Serialize, Deserialize)]
+struct HeaderConfig {
+    path: String,
+    vars: Vec<String>
 }
 
 #[derive(Serialize, Deserialize)]
 struct CoyoteLock {
     last_modified: HashMap<String, String>,
 
+    // commands (as `<target>:<command>`) that failed in the last build
+    #[serde(default)]
+    last_failed: Vec<String>,
+
+    // commands that finished successfully during a build that has not
+    // completed yet, so that an interrupted build can be resumed
+    #[serde(default)]
+    completed: Vec<String>,
+
+    // SHA-256 of the files checked by `hash` conditions
+    #[serde(default)]
+    last_hash: HashMap<String, String>,
+
+    // how long each command (as `<target>:<command>`) took, in milliseconds,
+    // the last time it succeeded
+    #[serde(default)]
+    durations: HashMap<String, u64>,
+
+    // where this lock file is read from and written to
+    #[serde(skip_serializing, skip_deserializing)]
+    path: String,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    resume: bool,
+
+    // timestamps from before the build started, which are what gets written
+    // to coyote.LOCK until the build completes
+    #[serde(skip_serializing, skip_deserializing)]
+    checkpoint_modified: HashMap<String, String>,
+
+    // likewise for the hashes of `hash` conditions
+    #[serde(skip_serializing, skip_deserializing)]
+    checkpoint_hash: HashMap<String, String>,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    retry_failed: bool,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    rebuild: bool,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    transient_exit_codes: Vec<i32>,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    transient_retries: u32,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    offline: bool,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    resolve_programs: bool,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    nice: Option<i32>,
+
+    // programs that have already been looked up during this build, by the
+    // PATH they were looked up in (`None` for the one coyote inherits)
+    #[serde(skip_serializing, skip_deserializing)]
+    program_paths: HashMap<(Option<String>, String), PathBuf>,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    container_runtime: Option<String>,
+
+    // the top level `shell`, split into its program and arguments
+    #[serde(skip_serializing, skip_deserializing)]
+    shell: Option<Vec<String>>,
+
     #[serde(skip_serializing, skip_deserializing)]
-    rebuild: bool
+    ascii: bool,
+
+    // when the build has to be finished by, set with `--max-time`
+    #[serde(skip_serializing, skip_deserializing)]
+    deadline: Option<Instant>,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    only_outputs_missing: bool,
+
+    // the top level `env`, after preprocessing
+    #[serde(skip_serializing, skip_deserializing)]
+    env: BTreeMap<String, String>,
+
+    // how long individual targets may take, set with `--target-timeout`
+    #[serde(skip_serializing, skip_deserializing)]
+    target_timeouts: HashMap<String, u64>,
+
+    // how many commands of a parallel target run at once
+    #[serde(skip_serializing, skip_deserializing)]
+    jobs: usize,
+
+    // how many commands print their streamed output at once, set with
+    // `--max-inflight-output`
+    #[serde(skip_serializing, skip_deserializing)]
+    max_inflight_output: Option<usize>
+}
+
+/// The settings needed to run a command, which (unlike the lock) can be
+/// shared between the threads of a parallel target
+struct RunSettings {
+    transient_exit_codes: Vec<i32>,
+    transient_retries: u32,
+    deadline: Option<Instant>,
+
+    // how many more commands may print their streamed output right now,
+    // when `--max-inflight-output` limits it
+    output_slots: Option<Arc<Mutex<usize>>>
+}
+
+/// When a command started, and how long it took (in milliseconds) the last
+/// time it succeeded
+struct CommandTiming {
+    started: Instant,
+    previous_ms: Option<u64>
+}
+
+/// Per-target state tracked between commands while an executable is being
+/// built, used by conditions that depend on how the build has gone so far
+struct BuildState {
+    previous_ran: bool
+}
+
+/// How the build of an executable ended
+#[derive(PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BuildOutcome {
+    Finished,
+
+    /// A command signalled that the rest of the build should be skipped
+    Stopped,
+
+    Failed,
+
+    /// The build ran past `--max-time`
+    TimedOut
+}
+
+#[derive(PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CommandStatus {
+    Succeeded,
+    Failed,
+    Skipped,
+    Stopped
+}
+
+#[derive(Serialize)]
+struct CommandResult {
+    command: String,
+    status: CommandStatus,
+    exit_code: Option<i32>,
+    duration_ms: u128,
+
+    // why the command failed, for the summary of a `--keep-going` build
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>
+}
+
+/// The result of building a single executable, as written by `--result-dir`
+#[derive(Serialize)]
+struct TargetResult {
+    target: String,
+    outcome: BuildOutcome,
+    duration_ms: u128,
+    commands: Vec<CommandResult>
+}
+
+/// Totals over every command in a build
+#[derive(Serialize)]
+struct CommandCounts {
+    ran: usize,
+    skipped: usize,
+    failed: usize,
+    run_time_ms: u128
+}
+
+/// An error reported during a build, as it appears in the build summary
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Clone)]
+struct ErrorReport {
+    subname: Option<String>,
+    message: String,
+    fatal: bool
+}
+
+/// The report of a whole build, as written by `--summary-json` and printed
+/// by `--format json`
+#[derive(Serialize)]
+struct BuildSummary<'a> {
+    project_name: &'a str,
+    outcome: BuildOutcome,
+    duration_ms: u128,
+    #[serde(flatten)]
+    counts: CommandCounts,
+    targets: &'a [TargetResult],
+    errors: Vec<ErrorReport>
+}
+
+/// A target as listed by `--print-targets-json`
+#[derive(Serialize)]
+struct TargetInfo<'a> {
+    name: &'a str,
+    description: Option<&'a str>,
+    command_count: usize,
+    depends: Vec<&'a str>,
+    extends: Option<&'a str>
 }
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    subcommand: Option<Subcommands>,
+
     /// Recipe for coyote to build
     recipe: Option<String>,
 
+    /// Directory to look for recipes in, instead of the current directory
+    #[arg(long, value_name = "DIR", global = true)]
+    recipe_dir: Option<String>,
+
+    /// Lock file to use instead of coyote.LOCK (or coyote-<recipe>.LOCK when
+    /// building a recipe)
+    #[arg(long, value_name = "PATH", global = true)]
+    lockfile: Option<String>,
+
     /// Rebuilds the entire recipe regardless of coyote.LOCK
     /// (ignores `run_if` etc.)
     #[arg(short, long, default_value_t = false)]
-    rebuild: bool
+    rebuild: bool,
+
+    /// Only builds the given target (and the targets it depends on), instead
+    /// of the entry target or every target
+    #[arg(short, long)]
+    target: Option<String>,
+
+    /// Asks which targets to build (the targets they depend on are built as
+    /// well). Builds as usual when not run from a terminal
+    #[arg(short, long, default_value_t = false,
+        conflicts_with_all = ["target", "watch"])]
+    interactive: bool,
+
+    /// Sets a variable, taking the place of any variable of the same name in
+    /// the build file (can be given more than once)
+    #[arg(long = "var", value_name = "KEY=VALUE")]
+    vars: Vec<String>,
+
+    /// Runs at most N commands of a parallel target at once (`auto` or 0 for
+    /// one per CPU, which is the default). Targets still build one after
+    /// another
+    #[arg(short, long, value_name = "N|auto", value_parser = parse_jobs)]
+    jobs: Option<usize>,
+
+    /// Prints the streamed output of at most N commands of a parallel target
+    /// at once. The others hold theirs back until they get a turn or finish
+    #[arg(long, value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..))]
+    max_inflight_output: Option<u64>,
+
+    /// Prints the commands that would run (and the ones that would be
+    /// skipped) without running anything or touching coyote.LOCK
+    #[arg(short = 'n', long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Shows backtick substitutions as they are written during a dry run,
+    /// instead of running them to show their output
+    #[arg(long, default_value_t = false, requires = "dry_run")]
+    no_backticks: bool,
+
+    /// Keeps running after the build, and builds again whenever one of the
+    /// files named by a `run_if` condition (or the build file) changes
+    #[arg(short, long, default_value_t = false, conflicts_with = "dry_run")]
+    watch: bool,
+
+    /// Carries on building the other targets when a command fails (skipping
+    /// the targets that depend on it), and reports every failure at the end
+    #[arg(short, long, default_value_t = false)]
+    keep_going: bool,
+
+    /// Leaves out spinners and progress, only printing errors and the line
+    /// at the end of the build
+    #[arg(short, long, default_value_t = false, conflicts_with = "dry_run")]
+    quiet: bool,
+
+    /// Shows what backtick commands print to stderr, even when they succeed
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+
+    /// Prints the build's results (and any errors) as a single JSON document
+    /// at the end, instead of the usual human readable output
+    #[arg(long, value_name = "FORMAT", value_parser = ["human", "json"],
+        conflicts_with_all = ["dry_run", "list", "print_targets_json",
+            "dump_resolved", "print_command", "dump_env", "print_plan_dot"])]
+    format: Option<String>,
+
+    /// Resolves and prints a single command without running it, addressed as
+    /// <target>:<index> (eg. `build:0`)
+    #[arg(long, value_name = "PATH")]
+    print_command: Option<String>,
+
+    /// Prints the output of `--print-command` as a JSON array
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// Prints every target (with its description, number of commands and the
+    /// targets it depends on) as JSON, without running anything
+    #[arg(long, default_value_t = false)]
+    print_targets_json: bool,
+
+    /// Prints the build as a Graphviz DOT graph of every target's commands
+    /// and the targets they depend on, without running anything
+    #[arg(long, default_value_t = false)]
+    print_plan_dot: bool,
+
+    /// Lists the targets in the build file and the recipes in the current
+    /// directory, without running anything
+    #[arg(short, long, default_value_t = false)]
+    list: bool,
+
+    /// Writes the build file to PATH after every variable and backtick
+    /// substitution has been made, without building anything
+    #[arg(long, value_name = "PATH")]
+    dump_resolved: Option<String>,
+
+    /// Warns about variables that are declared but never referenced
+    #[arg(long, default_value_t = false)]
+    warn_unused_vars: bool,
+
+    /// Fails if any variables are declared but never referenced
+    #[arg(long, default_value_t = false, conflicts_with = "warn_unused_vars")]
+    error_unused_vars: bool,
+
+    /// Prints the environment that each command would run with, optionally
+    /// scoped to a single target or <target>:<index> command
+    #[arg(long, value_name = "SCOPE", num_args = 0..=1,
+        default_missing_value = "")]
+    dump_env: Option<String>,
+
+    /// Writes a JSON file with the result of each target into DIR as soon as
+    /// the target finishes
+    #[arg(long, value_name = "DIR")]
+    result_dir: Option<String>,
+
+    /// Refuses to run commands marked with `requires_network`, and sets
+    /// COYOTE_OFFLINE=1 for every command
+    #[arg(long, default_value_t = false)]
+    offline: bool,
+
+    /// Prints a summary of how many commands ran or were skipped at the end
+    /// of the build
+    #[arg(long, default_value_t = false)]
+    stats: bool,
+
+    /// Spawns commands by name, rather than resolving each program against
+    /// PATH once before the build starts
+    #[arg(long, default_value_t = false)]
+    no_resolve: bool,
+
+    /// Doesn't check that every program is on PATH before the build starts,
+    /// for programs that are only installed by the build itself
+    #[arg(long, default_value_t = false)]
+    skip_program_check: bool,
+
+    /// Only re-runs the commands that failed in the last build
+    #[arg(long, default_value_t = false)]
+    retry_failed: bool,
+
+    /// Continues an interrupted build, skipping the commands that already
+    /// finished successfully
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    /// Builds targets in order of their names rather than the order they are
+    /// declared in
+    #[arg(long, default_value_t = false)]
+    sorted: bool,
+
+    /// Only builds (and numbers) the targets whose `if` expression is true,
+    /// without reporting the ones that were left out
+    #[arg(long, default_value_t = false)]
+    only_enabled: bool,
+
+    /// Prints ASCII symbols instead of emoji, even if the terminal supports
+    /// them
+    #[arg(long, default_value_t = false)]
+    ascii: bool,
+
+    /// Prepends a label to every line of build output, to tell it apart
+    /// from other tools in aggregated logs
+    #[arg(long, value_name = "STRING", default_value = "")]
+    prefix: String,
+
+    /// Aborts the build (killing the running command) if it takes longer
+    /// than SECONDS, without updating coyote.LOCK
+    #[arg(long, value_name = "SECONDS")]
+    max_time: Option<u64>,
+
+    /// Aborts the build if the given target takes longer than SECONDS to
+    /// build (eg. `test=300`). Can be given more than once
+    #[arg(long, value_name = "TARGET=SECONDS",
+        value_parser = parse_target_timeout)]
+    target_timeout: Vec<(String, u64)>,
+
+    /// Builds from this file or URL instead of coyote.json (coyote.LOCK is
+    /// still kept in the current directory)
+    #[arg(long, value_name = "PATH|URL", conflicts_with = "recipe")]
+    config: Option<String>,
+
+    /// Allows `--config` to fetch the build file over HTTP(S)
+    #[arg(long, default_value_t = false)]
+    allow_remote: bool,
+
+    /// Expected SHA-256 of the file given to `--config`
+    #[arg(long, value_name = "HEX")]
+    config_sha256: Option<String>,
+
+    /// Writes a JSON report of every target and command to PATH when the
+    /// build ends, even if it fails
+    #[arg(long, value_name = "PATH")]
+    summary_json: Option<String>,
+
+    /// Writes a CSV of every command's target, duration and status to PATH
+    /// when the build ends
+    #[arg(long, value_name = "PATH")]
+    print_durations_csv: Option<String>,
+
+    /// Only runs commands with `outputs` if any of those outputs are missing,
+    /// ignoring their `run_if` conditions
+    #[arg(long, default_value_t = false)]
+    only_outputs_missing: bool
 }
 
-fn format_error(message: &str, fatal: bool, subname: &str) {
-    let mut msg: String = String::new();
-    if subname.is_empty() {
-        msg = format!("[{}] ", style("coyote").red());
+#[derive(Subcommand)]
+enum Subcommands {
+    /// Rewrites coyote.json (or a recipe) with consistent formatting
+    Fmt {
+        /// Recipe to format instead of coyote.json
+        recipe: Option<String>,
+
+        /// Exits with an error instead of rewriting the file if it is not
+        /// already formatted
+        #[arg(long, default_value_t = false)]
+        check: bool,
+
+        /// Rewrites YAML and TOML files even if that loses their comments
+        #[arg(long, default_value_t = false)]
+        force: bool
+    },
+
+    /// Checks that every file used by a `run_if` condition (or as stdin)
+    /// exists, without building anything
+    VerifyInputs {
+        /// Recipe to check instead of coyote.json
+        recipe: Option<String>
+    },
+
+    /// Deletes the lock file, so that the next build starts from scratch
+    Clean {
+        /// Recipe whose outputs are deleted instead of coyote.json's
+        recipe: Option<String>,
+
+        /// Also deletes the `outputs` of every command
+        #[arg(long, default_value_t = false)]
+        outputs: bool,
+
+        /// Lets `--outputs` delete outputs that are directories (and
+        /// everything in them)
+        #[arg(long, default_value_t = false, requires = "outputs")]
+        dirs: bool
+    },
+
+    /// Replaces this coyote binary with the latest GitHub release
+    SelfUpdate {
+        /// Only reports whether a newer release is available
+        #[arg(long, default_value_t = false)]
+        check_only: bool
+    }
+}
+
+// prepended to every line of build output, set with `--prefix`
+static OUTPUT_PREFIX: OnceLock<String> = OnceLock::new();
+
+// directory that recipes (`coyote-<recipe>.json` etc.) are looked up in, set
+// with `--recipe-dir`
+static RECIPE_DIR: OnceLock<String> = OnceLock::new();
+
+// set by `--quiet` once the build starts, to leave out spinners and progress
+static QUIET: OnceLock<bool> = OnceLock::new();
+
+// set by `--format json`, which replaces all of the human readable output
+static JSON_OUTPUT: OnceLock<bool> = OnceLock::new();
+
+// set by `--verbose`, to show what backtick commands print to stderr even when
+// they succeed
+static VERBOSE: OnceLock<bool> = OnceLock::new();
+
+// set by `--no-backticks`, to leave backtick substitutions unexpanded
+static NO_BACKTICKS: OnceLock<bool> = OnceLock::new();
+
+// every error reported so far, for the build summary
+static ERRORS: Mutex<Vec<ErrorReport>> = Mutex::new(Vec::new());
+
+// names of the variables that have been substituted somewhere, for
+// `--warn-unused-vars`
+static USED_VARIABLES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// `println!`, but with the `--prefix` in front of every line. Nothing is
+/// printed with `--quiet`
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !quiet() {
+            say_always!($($arg)*)
+        }
+    };
+}
+
+/// `say!` for the lines that are printed even with `--quiet` (but not with
+/// `--format json`)
+macro_rules! say_always {
+    ($($arg:tt)*) => {
+        if !json_output() {
+            println!("{}", prefixed(&format!($($arg)*)))
+        }
+    };
+}
+
+fn quiet() -> bool {
+    QUIET.get().copied().unwrap_or(false)
+}
+
+fn json_output() -> bool {
+    JSON_OUTPUT.get().copied().unwrap_or(false)
+}
+
+fn recipe_dir() -> &'static str {
+    RECIPE_DIR.get().map(String::as_str).unwrap_or(".")
+}
+
+fn verbose() -> bool {
+    VERBOSE.get().copied().unwrap_or(false)
+}
+
+fn no_backticks() -> bool {
+    NO_BACKTICKS.get().copied().unwrap_or(false)
+}
+
+fn output_prefix() -> &'static str {
+    OUTPUT_PREFIX.get().map(String::as_str).unwrap_or("")
+}
+
+fn prefixed(message: &str) -> String {
+    let prefix = output_prefix();
+    if prefix.is_empty() {
+        return message.to_string();
+    }
+
+    message
+        .split('\n')
+        .map(|line| format!("{}{}", prefix, line))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Picks between an emoji and its ASCII fallback. The fallback is used when
+/// it is forced with `--ascii`, or when the terminal cannot show emoji
+fn pick_symbol<'a>(emoji: &Emoji<'a, 'a>, ascii: bool, wants_emoji: bool)
+    -> &'a str {
+    if ascii || !wants_emoji {
+        emoji.1
+    } else {
+        emoji.0
+    }
+}
+
+fn symbol<'a>(emoji: &Emoji<'a, 'a>, ascii: bool) -> &'a str {
+    pick_symbol(emoji, ascii, Term::stdout().features().wants_emoji())
+}
+
+fn print_error(message: &str, fatal: bool, subname: &str) {
+    print_error_with_output(message, "", fatal, subname);
+}
+
+/// Like `print_error`, followed by the output of the command that failed. The
+/// output is indented and dimmed, so that it stands apart from coyote's own
+/// messages
+fn print_error_with_output(
+    message: &str,
+    output: &str,
+    fatal: bool,
+    subname: &str) {
+    if let Ok(mut errors) = ERRORS.lock() {
+        let message = if output.is_empty() {
+            message.to_string()
+        } else {
+            format!("{}\n\n{}", message, output)
+        };
+
+        errors.push(ErrorReport {
+            subname: (!subname.is_empty()).then(|| subname.to_string()),
+            message,
+            fatal
+        });
+    }
+
+    if json_output() {
+        return;
+    }
+
+    let mut msg = if subname.is_empty() {
+        format!("[{}] ", style("coyote").red())
     } else {
-        msg = format!("[{}/{}] ",
+        format!("[{}/{}] ",
             style("coyote").red(),
             style(subname).color256(8)
-        );
-    }
-    msg += &message;
+        )
+    };
+    msg += message;
 
     if fatal {
         msg += format!(" ({})", style("fatal").red().bright()).as_str();
     }
 
-    eprintln!("{}", msg);
+    if !output.trim().is_empty() {
+        msg += "\n";
+        for line in output.trim_end().lines() {
+            msg += format!("\n    {}", style(line).dim()).as_str();
+        }
+        msg += "\n";
+    }
+
+    eprintln!("{}", prefixed(&msg));
+}
+
+fn format_error(message: &str, fatal: bool, subname: &str) {
+    print_error(message, fatal, subname);
+    exit_with_errors();
+}
+
+fn exit_with_errors() -> ! {
+    // the build summary can't be printed from here, so the output is just
+    // the errors
+    if json_output() {
+        let errors = ERRORS.lock().map(|errors| errors.clone())
+            .unwrap_or_default();
+        let report = serde_json::json!({
+            "outcome": BuildOutcome::Failed,
+            "errors": errors
+        });
+        println!("{}", serde_json::to_string_pretty(&report)
+            .unwrap_or_default());
+    }
     process::exit(-1);
 }
 
 fn execute_command_opt(
     command: Option<Vec<String>>,
-    command_string: &String) -> String {
+    command_string: &str,
+    cwd: Option<&str>) -> String {
     let mut cmd = match command {
         Some(c) => c,
         None => {
@@ -94,6 +945,9 @@ fn execute_command_opt(
 
     let mut cmd_process = process::Command::new(cmd[0].clone());
     cmd_process.args(&mut cmd[1..]);
+    if let Some(cwd) = cwd {
+        cmd_process.current_dir(cwd);
+    }
 
     if let Ok(output) = cmd_process.output() {
         // check if output is damaged
@@ -103,20 +957,24 @@ fn execute_command_opt(
                 Ok(v) => v,
                 Err(_) => process::exit(-1)
             }.to_owned();
-            format_error(
-                format!("Failed to execute command '{}':\n\n{}",
-                    command_string,
-                    s
-                ).as_str(),
+            print_error_with_output(
+                format!("Failed to execute command '{}':", command_string)
+                    .as_str(),
+                &s,
                 true,
                 "preprocessor"
             );
-            eprintln!(
-                "[coyote/preprocessor] Failed to execute command '{}':\n\n{}",
-                command_string,
-                s
-            );
-            process::exit(-1);
+            exit_with_errors();
+        }
+
+        // warnings from a command that succeeded are otherwise lost
+        if verbose() {
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                eprintln!("{}", prefixed(&format!("[{}/{}] {}",
+                    style("coyote").yellow(),
+                    style("preprocessor").color256(8),
+                    line)));
+            }
         }
 
         // convert stdout into a string, and pass that as the command output
@@ -125,7 +983,7 @@ fn execute_command_opt(
             Err(_) => process::exit(-1)
         }.to_owned();
 
-        return out;
+        out
     } else {
         format_error(
             format!("Failed to execute command '{}'", command_string).as_str(),
@@ -136,85 +994,199 @@ fn execute_command_opt(
     }
 }
 
-fn patch_variable_references(value: &String,
-    variables: &HashMap<String, String>) -> Result<String, String> {
-    let mut tokens: String = String::new();
-    let mut var_data: String = String::new();
-    let mut var_found = false;
+/// Appends resolved text to the innermost open variable reference, or to the
+/// output if no reference is currently open. When `escape` is set, braces
+/// written to the output are escaped so that it can be patched again later
+fn emit_resolved(
+    references: &mut [String],
+    output: &mut String,
+    text: &str,
+    escape: bool)
+{
+    match references.last_mut() {
+        Some(reference) => reference.push_str(text),
+        None if escape => output.push_str(&text.replace('{', "{{")),
+        None => output.push_str(text)
+    }
+}
 
-    for c in value.chars() {
-        if var_found {
-            if c == '}' {
-                // variable ended
-                let var_ref = tokens.replace("{", "");
-                match variables.get(&var_ref) {
-                    Some(value) => var_data += value,
-                    None => return Err(var_ref)
-                }
-
-                var_found = false;
-            } else if c == '{' {
-                // escape
-                var_found = false;
-                var_data.push('{');
-            } else {
-                tokens.push(c);
+/// Handles a single character while inside of a variable reference.
+/// References may be nested (eg. `{cc_for_{os}}`), in which case the innermost
+/// reference is resolved first and its value becomes part of the name of the
+/// enclosing one. Returns the name of the first undefined variable on failure.
+///
+/// References to `deferred` variables (whose values are only known once the
+/// build is running) are kept as-is, with everything else escaped so that the
+/// result can be patched again once their values are known
+fn patch_reference_char(
+    c: char,
+    references: &mut Vec<String>,
+    output: &mut String,
+    variables: &HashMap<String, String>,
+    deferred: Option<&HashSet<String>>) -> Result<(), String>
+{
+    let escape = deferred.is_some();
+
+    if c == '}' {
+        // variable ended
+        let var_ref = references.pop().unwrap_or_default();
+
+        // the text after `:-` is used if the variable is not defined
+        let (reference, default) = match var_ref.split_once(":-") {
+            Some((reference, default)) => (reference, Some(default)),
+            None => (var_ref.as_str(), None)
+        };
+
+        // list variables are joined with spaces, unless the reference asks
+        // for another separator (eg. `{sources:join(,)}`)
+        let (name, separator) = match reference.split_once(":join(") {
+            Some((name, rest)) if rest.ends_with(')') =>
+                (name, &rest[..rest.len() - 1]),
+            _ => (reference, " ")
+        };
+
+        if deferred.is_some_and(|deferred| deferred.contains(name)) {
+            emit_resolved(references, output, &format!("{{{}}}", var_ref),
+                false);
+            return Ok(());
+        }
+
+        // `{env:NAME}` and `{$NAME}` read from the environment instead
+        let environment = name
+            .strip_prefix("env:")
+            .or_else(|| name.strip_prefix('$'));
+        let value = match environment {
+            Some(key) => std::env::var(key).ok(),
+            None => variables.get(name).cloned()
+        };
+
+        match (value, default) {
+            (Some(value), _) => emit_resolved(references, output,
+                &join_list(&value, separator), escape),
+            (None, Some(default)) => {
+                emit_resolved(references, output, default, escape);
+                return Ok(());
+            },
+            (None, None) => return Err(name.to_string())
+        }
+
+        if environment.is_none() {
+            if let Ok(mut used) = USED_VARIABLES.lock() {
+                used.insert(name.to_string());
             }
-        } else if c == '{' {
-            var_found = true;
-            tokens = "{".to_string();
+        }
+    } else if c == '{' {
+        if references.last().is_some_and(|reference| reference.is_empty()) {
+            // escape
+            references.pop();
+            emit_resolved(references, output, "{", escape);
         } else {
+            references.push(String::new());
+        }
+    } else if let Some(reference) = references.last_mut() {
+        reference.push(c);
+    }
+
+    Ok(())
+}
+
+/// Undoes the escaping of braces in a value that still has references to
+/// deferred variables, leaving those references in place
+fn unescape_braces(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '{' {
+            chars.next_if_eq(&'{');
+        }
+        unescaped.push(c);
+    }
+
+    unescaped
+}
+
+fn patch_variable_references(value: &str,
+    variables: &HashMap<String, String>,
+    deferred: Option<&HashSet<String>>) -> Result<String, String> {
+    let mut references: Vec<String> = Vec::new();
+    let mut var_data: String = String::new();
+
+    for c in value.chars() {
+        if references.is_empty() && c != '{' {
             var_data.push(c);
+        } else {
+            patch_reference_char(
+                c,
+                &mut references,
+                &mut var_data,
+                variables,
+                deferred
+            )?;
         }
     }
 
     Ok(var_data)
 }
 
-fn patch_string(value: &String, variables: &HashMap<String, String>) ->
-    Result<String, String>
+/// Substitutes variable references and backtick commands in a string. The
+/// commands run in `cwd` if it is given
+fn patch_string(
+    value: &str,
+    variables: &HashMap<String, String>,
+    cwd: Option<&str>) -> Result<String, String>
 {
     let mut tokens: String = String::new();
+    let mut references: Vec<String> = Vec::new();
     let mut var_data: String = String::new();
-    let mut var_found = false;
     let mut cmd_found = false;
+    let mut chars = value.chars().peekable();
 
+    while let Some(c) = chars.next() {
+        if !references.is_empty() {
+            patch_reference_char(
+                c,
+                &mut references,
+                &mut var_data,
+                variables,
+                None
+            )?;
+            continue;
+        }
 
-    for c in value.chars() {
-        if var_found {
-            if c == '}' {
-                // variable ended
-                let var_ref = tokens.replace("{", "");
-                match variables.get(&var_ref) {
-                    Some(value) => var_data += value,
-                    None => return Err(var_ref)
-                }
-
-                var_found = false;
-            } else if c == '{' {
-                // escape
-                var_found = false;
-                var_data.push('{');
-            } else {
+        // a doubled backtick is a literal backtick, in the same way that `{{`
+        // is a literal brace
+        let escaped = c == '`' && chars.next_if_eq(&'`').is_some();
+
+        if cmd_found {
+            if escaped {
                 tokens.push(c);
-            }
-        } else if cmd_found {
-            if c == '`' {
-                // command ended
-                let replace_cmd = tokens.replace("`", "");
-                let trimmed_cmd = replace_cmd.trim();
+            } else if c == '`' && no_backticks() {
+                // left as it was written (escaping any backticks again)
+                var_data += &format!("`{}`", tokens.replace('`', "``"));
+                cmd_found = false;
+            } else if c == '`' {
+                // command ended. Like `$(...)` in a shell, trailing whitespace
+                // is left out of the output unless the command starts with
+                // `raw:`
+                let trimmed_cmd = tokens.trim();
+                let raw_cmd = trimmed_cmd.strip_prefix("raw:");
+                let raw = raw_cmd.is_some();
+                let trimmed_cmd = raw_cmd.unwrap_or(trimmed_cmd);
                 let cmd = shlex::split(trimmed_cmd);
 
-                var_data += &execute_command_opt(cmd.clone(), &replace_cmd);
+                let output = execute_command_opt(cmd, &tokens, cwd);
+                var_data += if raw { &output } else { output.trim_end() };
+                cmd_found = false;
             } else {
                 tokens.push(c);
             }
+        } else if escaped {
+            var_data.push(c);
         } else if c == '{' {
-            var_found = true;
-            tokens = "{".to_string();
+            references.push(String::new());
         } else if c == '`' {
             cmd_found = true;
-            tokens = "`".to_string();
+            tokens.clear();
         } else {
             var_data.push(c);
         }
@@ -223,6 +1195,399 @@ fn patch_string(value: &String, variables: &HashMap<String, String>) ->
     Ok(var_data)
 }
 
+/// Finds the byte index of the `}` closing the brace opened at `start`
+fn matching_brace(value: &str, start: usize) -> Option<usize> {
+    let mut depth = 0;
+
+    for (i, c) in value[start..].char_indices() {
+        if c == '{' {
+            depth += 1;
+        } else if c == '}' {
+            depth -= 1;
+            if depth == 0 {
+                return Some(start + i);
+            }
+        }
+    }
+
+    None
+}
+
+/// Splits the contents of a brace group into its alternatives, returning
+/// `None` if the group is not a brace expansion (eg. a variable reference).
+/// Groups are either comma separated lists (`{a,b,c}`) or ranges of integers
+/// or single characters (`{1..3}`, `{a..e}`)
+fn brace_alternatives(group: &str) -> Option<Vec<String>> {
+    // the separator of a join (or a default value) can contain a comma
+    if group.contains(":join(") || group.contains(":-") {
+        return None;
+    }
+
+    let mut alternatives: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+
+    for c in group.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                alternatives.push(std::mem::take(&mut current));
+                continue;
+            },
+            _ => {}
+        }
+        current.push(c);
+    }
+
+    if !alternatives.is_empty() {
+        alternatives.push(current);
+        return Some(alternatives);
+    }
+
+    let (start, end) = group.split_once("..")?;
+
+    if let (Ok(start), Ok(end)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let range: Vec<i64> = if start <= end {
+            (start..=end).collect()
+        } else {
+            (end..=start).rev().collect()
+        };
+
+        return Some(range.iter().map(|i| i.to_string()).collect());
+    }
+
+    let mut start_chars = start.chars();
+    let mut end_chars = end.chars();
+    match (start_chars.next(), start_chars.next(), end_chars.next(),
+        end_chars.next()) {
+        (Some(start), None, Some(end), None) => {
+            let range: Vec<char> = if start <= end {
+                (start..=end).collect()
+            } else {
+                (end..=start).rev().collect()
+            };
+
+            Some(range.iter().map(|c| c.to_string()).collect())
+        },
+        _ => None
+    }
+}
+
+/// Expands shell-like brace expressions (eg. `img{1..3}.png` or `{a,b}.o`)
+/// into multiple strings. This runs on the raw text before variables are
+/// substituted, so escaped braces (`{{`) and variable references are left
+/// alone, and braces in variable values are never expanded
+fn expand_braces(value: &str) -> Vec<String> {
+    let mut i = 0;
+
+    while let Some(offset) = value[i..].find('{') {
+        let start = i + offset;
+
+        // escape
+        if value[start + 1..].starts_with('{') {
+            i = start + 2;
+            continue;
+        }
+
+        let end = match matching_brace(value, start) {
+            Some(end) => end,
+            None => break
+        };
+
+        match brace_alternatives(&value[start + 1..end]) {
+            Some(alternatives) => {
+                let prefix = &value[..start];
+                let suffix = &value[end + 1..];
+
+                return alternatives
+                    .iter()
+                    .flat_map(|alternative| expand_braces(
+                        &format!("{}{}{}", prefix, alternative, suffix)
+                    ))
+                    .collect();
+            },
+            // a variable reference
+            None => i = end + 1
+        }
+    }
+
+    vec![value.to_string()]
+}
+
+#[derive(PartialEq)]
+enum ExpressionToken {
+    Value(String),
+    Equal,
+    NotEqual,
+    And,
+    Or,
+    Not,
+    Open,
+    Close
+}
+
+/// Splits an `if` expression into tokens, substituting variable references
+/// inside of each value (so that values containing spaces or operators don't
+/// change the structure of the expression)
+fn tokenize_expression(expression: &str, variables: &HashMap<String, String>)
+    -> Result<Vec<ExpressionToken>, String> {
+    let mut tokens: Vec<ExpressionToken> = Vec::new();
+    let mut chars = expression.chars().peekable();
+
+    let patch = |value: &str| patch_variable_references(value, variables, None)
+        .map_err(|reference| format!("'{}' is not defined", reference));
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => ExpressionToken::Open,
+            ')' => ExpressionToken::Close,
+            '=' | '&' | '|' => {
+                if chars.next() != Some(c) {
+                    return Err(format!("expected '{}{}'", c, c));
+                }
+
+                match c {
+                    '=' => ExpressionToken::Equal,
+                    '&' => ExpressionToken::And,
+                    _ => ExpressionToken::Or
+                }
+            },
+            '!' => {
+                if chars.peek() == Some(&'=') {
+                    chars.next();
+                    ExpressionToken::NotEqual
+                } else {
+                    ExpressionToken::Not
+                }
+            },
+            '\'' | '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(next) if next == c => break,
+                        Some(next) => value.push(next),
+                        None => return Err("unterminated string".to_string())
+                    }
+                }
+
+                ExpressionToken::Value(patch(&value)?)
+            },
+            _ => {
+                let mut value = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "()=!&|".contains(next) {
+                        break;
+                    }
+
+                    value.push(next);
+                    chars.next();
+                }
+
+                ExpressionToken::Value(patch(&value)?)
+            }
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// A small recursive descent evaluator for `if` expressions, supporting
+/// `==`, `!=`, `&&`, `||`, `!` and parentheses. A value on its own is true
+/// unless it is empty, `false` or `0`
+struct ExpressionParser {
+    tokens: Vec<ExpressionToken>,
+    position: usize
+}
+
+impl ExpressionParser {
+    fn next(&mut self) -> Option<&ExpressionToken> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn accept(&mut self, token: ExpressionToken) -> bool {
+        if self.tokens.get(self.position) == Some(&token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut value = self.and()?;
+        while self.accept(ExpressionToken::Or) {
+            // both sides are always parsed, so that errors are always caught
+            let right = self.and()?;
+            value = value || right;
+        }
+
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut value = self.comparison()?;
+        while self.accept(ExpressionToken::And) {
+            let right = self.comparison()?;
+            value = value && right;
+        }
+
+        Ok(value)
+    }
+
+    fn comparison(&mut self) -> Result<bool, String> {
+        if self.accept(ExpressionToken::Not) {
+            return Ok(!self.comparison()?);
+        }
+
+        if self.accept(ExpressionToken::Open) {
+            let value = self.or()?;
+            if !self.accept(ExpressionToken::Close) {
+                return Err("expected ')'".to_string());
+            }
+
+            return Ok(value);
+        }
+
+        let left = self.value()?;
+        if self.accept(ExpressionToken::Equal) {
+            Ok(left == self.value()?)
+        } else if self.accept(ExpressionToken::NotEqual) {
+            Ok(left != self.value()?)
+        } else {
+            Ok(!(left.is_empty() || left == "false" || left == "0"))
+        }
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(ExpressionToken::Value(value)) => Ok(value.clone()),
+            Some(_) => Err("expected a value".to_string()),
+            None => Err("unexpected end of expression".to_string())
+        }
+    }
+}
+
+fn evaluate_expression(expression: &str, variables: &HashMap<String, String>)
+    -> Result<bool, String> {
+    let mut parser = ExpressionParser {
+        tokens: tokenize_expression(expression, variables)?,
+        position: 0
+    };
+
+    let value = parser.or()?;
+    if parser.position < parser.tokens.len() {
+        return Err("unexpected trailing tokens".to_string());
+    }
+
+    Ok(value)
+}
+
+/// Evaluates an `if` expression, exiting with an error if it is invalid
+fn check_expression(
+    expression: &str,
+    variables: &HashMap<String, String>,
+    location: &str) -> bool
+{
+    match evaluate_expression(expression, variables) {
+        Ok(value) => value,
+        Err(error) => {
+            format_error(format!("Invalid 'if' expression '{}' in {}: {}",
+                expression, location, error).as_str(), true, "if");
+            process::exit(-1);
+        }
+    }
+}
+
+/// Resolves an object of variable declarations on top of the variables that
+/// are already defined, which the declarations can reference. Arrays become
+/// list variables, with each item resolved on its own
+fn resolve_variables(
+    declarations: &serde_json::Value,
+    mut variables: HashMap<String, String>) -> HashMap<String, String> {
+    let Some(declarations) = declarations.as_object() else {
+        format_error(format!("'variables' must be an object, not {}",
+            json_type(declarations)).as_str(), true, "preprocessor");
+        process::exit(-1);
+    };
+
+    for (k, v) in declarations {
+        let key = k.as_str().to_string();
+
+        // numbers and booleans are used as they are written
+        let patch = |value: &serde_json::Value| match value {
+            serde_json::Value::String(value) => check_var_string(
+                patch_string(value, &variables, None),
+                key.clone()
+            ),
+            serde_json::Value::Number(number) => number.to_string(),
+            serde_json::Value::Bool(boolean) => boolean.to_string(),
+            other => {
+                format_error(format!("Variable '{}' is {}, but variables can \
+                    only be strings, numbers, booleans or lists of them", key,
+                    json_type(other)).as_str(), true, "preprocessor");
+                process::exit(-1);
+            }
+        };
+
+        let value = match v.as_array() {
+            Some(items) => make_list(items.iter().map(patch).collect()),
+            None => patch(v)
+        };
+        variables.insert(key, value);
+    }
+
+    variables
+}
+
+/// The name of the type of a JSON value, for error messages
+fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "a list",
+        serde_json::Value::Object(_) => "an object"
+    }
+}
+
+/// Stores the items of a list variable as a single string, starting with the
+/// separator so that even an empty list is told apart from a string
+fn make_list(items: Vec<String>) -> String {
+    format!("{}{}", LIST_SEPARATOR, items.join(&LIST_SEPARATOR.to_string()))
+}
+
+/// The items of a list variable, or `None` if the variable is not a list
+fn list_items(value: &str) -> Option<Vec<&str>> {
+    match value.strip_prefix(LIST_SEPARATOR)? {
+        "" => Some(Vec::new()),
+        items => Some(items.split(LIST_SEPARATOR).collect())
+    }
+}
+
+/// The value of a variable as it is substituted, which for lists is their
+/// items joined with `separator`
+fn join_list<'a>(value: &'a str, separator: &str) -> std::borrow::Cow<'a, str> {
+    match list_items(value) {
+        Some(items) => items.join(separator).into(),
+        None => value.into()
+    }
+}
+
+/// A variable as JSON, which is an array for list variables
+fn variable_json(value: &str) -> serde_json::Value {
+    match list_items(value) {
+        Some(items) => serde_json::json!(items),
+        None => serde_json::json!(value)
+    }
+}
+
 fn check_var_string(string: Result<String, String>, key: String) -> String {
     match string {
         Ok(value) => value,
@@ -254,9 +1619,130 @@ fn get_file_modified_time(path: String) -> u64 {
     }
 }
 
-fn condition_met(cond: &Vec<String>, target: String, lock: &mut CoyoteLock)
-    -> bool {
-    if cond.len() == 0 {
+/// The SHA-256 of a file as hex, reading it in chunks
+fn file_hash(path: &str) -> Option<String> {
+    let mut file = fs::File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    let mut chunk = [0u8; 64 * 1024];
+
+    loop {
+        match file.read(&mut chunk).ok()? {
+            0 => break,
+            read => hasher.update(&chunk[..read])
+        }
+    }
+
+    Some(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Checks whether a file contains some text, reading it in chunks so that
+/// large files are never loaded all at once. Missing files contain nothing
+fn file_contains(path: &str, needle: &[u8]) -> bool {
+    let Ok(mut file) = fs::File::open(path) else {
+        return false;
+    };
+
+    if needle.is_empty() {
+        return true;
+    }
+
+    // the tail of each chunk is kept, so that matches spanning two chunks are
+    // still found
+    let mut window: Vec<u8> = Vec::new();
+    let mut chunk = [0u8; 64 * 1024];
+
+    loop {
+        let read = match file.read(&mut chunk) {
+            Ok(0) | Err(_) => return false,
+            Ok(read) => read
+        };
+
+        window.extend_from_slice(&chunk[..read]);
+        if window.windows(needle.len()).any(|bytes| bytes == needle) {
+            return true;
+        }
+
+        let keep = window.len().min(needle.len() - 1);
+        window.drain(..window.len() - keep);
+    }
+}
+
+/// Checks a `run_if` condition, which is either a single condition or
+/// `all`/`any` followed by the conditions it combines. Every one of the
+/// combined conditions is checked (even once the result is known), so that
+/// each records its timestamps and hashes in the same way it would alone
+fn run_if_met(
+    cond: &[ConditionToken],
+    target: &str,
+    lock: &mut CoyoteLock,
+    state: &BuildState) -> bool {
+    let (first, rest) = match cond.split_first() {
+        Some((ConditionToken::Word(word), rest)) => (word.as_str(), rest),
+        // a bare list of conditions is the same as `all`
+        Some((ConditionToken::Condition(_), _)) => ("all", cond),
+        None => ("", cond)
+    };
+
+    // `!` followed by words negates a single condition, which
+    // `condition_met` takes care of
+    let negates_words = first == "!"
+        && matches!(rest.first(), Some(ConditionToken::Word(_)));
+
+    if matches!(first, "all" | "any" | "!") && !negates_words {
+        let expected = match first {
+            "!" => "a condition to negate",
+            _ => "a list of conditions"
+        };
+        let fail = || -> ! {
+            format_error(format!("Condition '{}' in target '{}' must be \
+                followed by {}", first, target, expected).as_str(), true,
+                "run_if");
+            process::exit(-1);
+        };
+
+        let conditions: Vec<&[ConditionToken]> = rest
+            .iter()
+            .map(|token| match token {
+                ConditionToken::Condition(condition) => condition.as_slice(),
+                ConditionToken::Word(_) => fail()
+            })
+            .collect();
+        if conditions.is_empty() || (first == "!" && conditions.len() != 1) {
+            fail();
+        }
+
+        let mut results = conditions
+            .into_iter()
+            .map(|condition| run_if_met(condition, target, lock, state));
+        return match first {
+            "all" => results.fold(true, |met, result| met & result),
+            "any" => results.fold(false, |met, result| met | result),
+            _ => !results.all(|result| result)
+        };
+    }
+
+    let words: Vec<String> = cond
+        .iter()
+        .map(|token| match token {
+            ConditionToken::Word(word) => word.clone(),
+            ConditionToken::Condition(_) => {
+                format_error(format!("Only 'all', 'any' and '!' conditions can \
+                    contain other conditions (in target '{}')", target)
+                    .as_str(), true, "run_if");
+                process::exit(-1);
+            }
+        })
+        .collect();
+
+    condition_met(&words, target.to_string(), lock, state)
+}
+
+fn condition_met(
+    cond: &[String],
+    target: String,
+    lock: &mut CoyoteLock,
+    state: &BuildState) -> bool {
+    if cond.is_empty() {
         format_error(format!(
             "No condition specifier for 'run_if' in target '{}'", target)
             .as_str(),
@@ -301,235 +1787,3787 @@ fn condition_met(cond: &Vec<String>, target: String, lock: &mut CoyoteLock)
 
             last_modified != file_modified_time
         }
-        _ => {
-            format_error(
-                format!("Unknown condition type '{}' in target '{}'",
-                    cond[0],
-                    target)
-                .as_str(),
-                true,
-                "run_if"
-            );
-            false
+        "glob" => {
+            if cond.len() != 2 {
+                format_error(format!("Condition 'glob' in target '{}' must \
+                    have 1 argument: <pattern>", target).as_str(), true,
+                    "run_if");
+            }
+
+            let files = match glob::glob(&cond[1]) {
+                Ok(paths) => paths
+                    .flatten()
+                    .filter(|path| path.is_file())
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect::<Vec<String>>(),
+                Err(error) => {
+                    format_error(format!("Invalid pattern '{}' for condition \
+                        'glob' in target '{}': {}", cond[1], target, error)
+                        .as_str(), true, "run_if");
+                    process::exit(-1);
+                }
+            };
+
+            // the matching files are recorded along with their timestamps,
+            // so that adding or removing a file also counts as a change. They
+            // are kept under the pattern, apart from the timestamps recorded
+            // by `modified` for the same files
+            let listing = files
+                .into_iter()
+                .map(|file| format!("{} {}",
+                    get_file_modified_time(file.clone()), file))
+                .collect::<Vec<String>>()
+                .join("\n");
+            lock.last_modified.insert(format!("glob:{}", cond[1]),
+                listing.clone()) != Some(listing)
         }
-    }
-}
+        "not_modified" => {
+            if cond.len() != 2 {
+                format_error(format!("Condition 'not_modified' in target '{}' \
+                    must have 1 argument: <path>", target).as_str(), true,
+                    "run_if");
+            }
 
-impl CoyoteLock {
-    fn new() -> Self {
-        CoyoteLock {
-            last_modified: HashMap::new(),
-            rebuild: false
+            // the timestamp is recorded exactly as it is for `modified`
+            let modified = ["modified".to_string(), cond[1].clone()];
+            !condition_met(&modified, target, lock, state)
         }
-    }
-}
+        "!" => {
+            if cond.len() < 2 {
+                format_error(format!("Condition '!' in target '{}' must be \
+                    followed by a condition to negate", target).as_str(), true,
+                    "run_if");
+            }
 
-impl CoyoteJson {
-    fn preprocess(&mut self) {
-        // firstly, preprocess all of the variable declarations (eg. inserting
-        // variable references where $<name> is present, etc.)
-        let mut variables: HashMap<String, String> = HashMap::new();
+            !condition_met(&cond[1..], target, lock, state)
+        }
+        "hash" => {
+            if cond.len() != 2 {
+                format_error(format!("Condition 'hash' in target '{}' must \
+                    have 1 argument: <path>", target).as_str(), true, "run_if");
+            }
+
+            // unlike `modified`, touching the file without changing it does
+            // not count
+            let Some(hash) = file_hash(&cond[1]) else {
+                format_error(format!("Cannot read file '{}' for condition \
+                    'hash' in target '{}'", cond[1], target).as_str(), true,
+                    "run_if");
+                process::exit(-1);
+            };
+
+            lock.last_hash.insert(cond[1].clone(), hash.clone()) != Some(hash)
+        }
+        "after_ran" => {
+            if cond.len() > 1 {
+                format_error(format!("Condition 'after_ran' in target '{}' \
+                    takes no arguments", target).as_str(), true, "run_if");
+            }
+
+            // only true if the immediately preceding command in this target
+            // actually executed (the first command has no predecessor)
+            state.previous_ran
+        }
+        "exists" => {
+            if cond.len() != 2 {
+                format_error(format!("Condition 'exists' in target '{}' must \
+                    have 1 argument: <path>", target).as_str(), true, "run_if");
+            }
+
+            // the command runs to create the path, so the condition is only
+            // met while it is missing. A pattern (eg. `dist/*.whl`) is
+            // missing until something matches it
+            if !cond[1].contains(['*', '?', '[']) {
+                return fs::metadata(&cond[1]).is_err();
+            }
+
+            match glob::glob(&cond[1]) {
+                Ok(mut paths) => !paths.any(|path| path.is_ok()),
+                Err(error) => {
+                    format_error(format!("Invalid pattern '{}' for condition \
+                        'exists' in target '{}': {}", cond[1], target, error)
+                        .as_str(), true, "run_if");
+                    process::exit(-1);
+                }
+            }
+        }
+        "not_exists" => {
+            if cond.len() != 2 {
+                format_error(format!("Condition 'not_exists' in target '{}' \
+                    must have 1 argument: <path>", target).as_str(), true,
+                    "run_if");
+            }
+
+            // met once the path exists, or once anything matches a pattern
+            let exists = ["exists".to_string(), cond[1].clone()];
+            !condition_met(&exists, target, lock, state)
+        }
+        "env" => {
+            if cond.len() < 2 || cond.len() > 3 {
+                format_error(format!("Condition 'env' in target '{}' must \
+                    have 1 or 2 arguments: <name> [value]", target).as_str(),
+                    true, "run_if");
+            }
+
+            // without a value, any non-empty value of the variable will do
+            match (std::env::var(&cond[1]), cond.get(2)) {
+                (Ok(value), Some(expected)) => &value == expected,
+                (Ok(value), None) => !value.is_empty(),
+                (Err(_), _) => false
+            }
+        }
+        "contains" => {
+            if cond.len() != 3 {
+                format_error(format!("Condition 'contains' in target '{}' must \
+                    have 2 arguments: <path> <text>", target).as_str(), true,
+                    "run_if");
+            }
+
+            file_contains(&cond[1], cond[2].as_bytes())
+        }
+        "command" => {
+            if cond.len() < 2 {
+                format_error(format!("Condition 'command' in target '{}' must \
+                    have at least 1 argument: <program> [arguments...]", target)
+                    .as_str(), true, "run_if");
+            }
+
+            // a non-zero exit status just means the condition isn't met, and
+            // the predicate's output is of no interest
+            let status = process::Command::new(&cond[1])
+                .args(&cond[2..])
+                .envs(&lock.env)
+                .stdin(process::Stdio::null())
+                .stdout(process::Stdio::null())
+                .stderr(process::Stdio::null())
+                .status();
+
+            match status {
+                Ok(status) => status.success(),
+                Err(error) => {
+                    format_error(format!("Failed to run '{}' for condition \
+                        'command' in target '{}': {}", cond[1], target, error)
+                        .as_str(), true, "run_if");
+                    false
+                }
+            }
+        }
+        _ => {
+            format_error(
+                format!("Unknown condition type '{}' in target '{}'",
+                    cond[0],
+                    target)
+                .as_str(),
+                true,
+                "run_if"
+            );
+            false
+        }
+    }
+}
+
+impl CoyoteLock {
+    fn new() -> Self {
+        CoyoteLock {
+            last_modified: HashMap::new(),
+            last_failed: Vec::new(),
+            completed: Vec::new(),
+            last_hash: HashMap::new(),
+            durations: HashMap::new(),
+            path: "./coyote.LOCK".to_string(),
+            resume: false,
+            checkpoint_modified: HashMap::new(),
+            checkpoint_hash: HashMap::new(),
+            retry_failed: false,
+            rebuild: false,
+            transient_exit_codes: Vec::new(),
+            transient_retries: 0,
+            offline: false,
+            resolve_programs: false,
+            nice: None,
+            program_paths: HashMap::new(),
+            container_runtime: None,
+            shell: None,
+            ascii: false,
+            deadline: None,
+            only_outputs_missing: false,
+            env: BTreeMap::new(),
+            target_timeouts: HashMap::new(),
+            jobs: 1,
+            max_inflight_output: None
+        }
+    }
+
+    /// The PATH that a command's programs are found on: the one from the
+    /// command's own `env`, then the top level `env`, and otherwise the one
+    /// coyote inherits (`None`)
+    fn search_path(&self, command: Option<&Command>) -> Option<String> {
+        command
+            .and_then(|command| command.env.as_ref())
+            .and_then(|env| env.get("PATH"))
+            .or_else(|| self.env.get("PATH"))
+            .cloned()
+    }
+
+    /// Resolves a program name against `search` (like `which`), caching the
+    /// result for the rest of the build. Programs are left untouched when
+    /// resolution is off, but are still spawned by name only if they exist
+    fn resolve_program(&mut self, program: &str, search: Option<&str>)
+        -> Option<PathBuf> {
+        let path = self.find_program(program, search)?;
+        Some(if self.resolve_programs { path } else { PathBuf::from(program) })
+    }
+
+    /// Looks a program up on `search` regardless of `--no-resolve`, caching
+    /// the result. Programs that are already given as a path are returned
+    /// as they are
+    fn find_program(&mut self, program: &str, search: Option<&str>)
+        -> Option<PathBuf> {
+        if program.contains(std::path::MAIN_SEPARATOR)
+            || program.contains('/') {
+            return Some(PathBuf::from(program));
+        }
+
+        let key = (search.map(String::from), program.to_string());
+        if let Some(path) = self.program_paths.get(&key) {
+            return Some(path.clone());
+        }
+
+        let path = match search {
+            Some(search) => which::which_in(program, Some(search),
+                std::env::current_dir().ok()?).ok()?,
+            None => which::which(program).ok()?
+        };
+        self.program_paths.insert(key, path.clone());
+        Some(path)
+    }
+
+    /// Picks the first of a command's program and its fallbacks that can be
+    /// found on PATH, returning every program that was tried if none can
+    fn resolve_command(&mut self, command: &Command)
+        -> Result<PathBuf, Vec<String>> {
+        let search = self.search_path(Some(command));
+        let Some(fallback) = &command.fallback else {
+            return Ok(self
+                .resolve_program(&command.command, search.as_deref())
+                .unwrap_or_else(|| PathBuf::from(&command.command)));
+        };
+
+        let candidates: Vec<String> = std::iter::once(&command.command)
+            .chain(fallback)
+            .cloned()
+            .collect();
+
+        for program in &candidates {
+            if let Some(path) = self.resolve_program(program,
+                search.as_deref()) {
+                return Ok(path);
+            }
+        }
+
+        Err(candidates)
+    }
+
+    /// Returns the program used to run containers, which is the configured
+    /// one or otherwise the first of `CONTAINER_RUNTIMES` found on PATH
+    fn container_runtime(&mut self) -> Option<PathBuf> {
+        let search = self.search_path(None);
+        match self.container_runtime.clone() {
+            Some(runtime) => self.resolve_program(&runtime, search.as_deref()),
+            None => CONTAINER_RUNTIMES
+                .iter()
+                .find_map(|runtime| self.resolve_program(runtime,
+                    search.as_deref()))
+        }
+    }
+
+    /// Builds the process for a command, which is either the program itself
+    /// or, if the command runs in a container, the container runtime with
+    /// the project directory mounted as the working directory
+    fn process_for(&mut self, command: &Command, container: Option<&str>)
+        -> Result<process::Command, Vec<String>> {
+        let Some(image) = container else {
+            let mut cmd = match self.shell_for(command) {
+                Some(shell) => {
+                    let mut cmd = process::Command::new(&shell[0]);
+                    cmd.args(&shell[1..]).arg(command.command_line());
+                    cmd
+                },
+                None => {
+                    let mut cmd =
+                        process::Command::new(self.resolve_command(command)?);
+                    cmd.args(&command.arguments);
+                    cmd
+                }
+            };
+            cmd.envs(&self.env)
+                .envs(command.env.iter().flatten());
+            if let Some(cwd) = &command.cwd {
+                cmd.current_dir(cwd);
+            }
+            return Ok(cmd);
+        };
+
+        let Some(runtime) = self.container_runtime() else {
+            return Err(match &self.container_runtime {
+                Some(runtime) => vec![runtime.clone()],
+                None => CONTAINER_RUNTIMES
+                    .iter()
+                    .map(|runtime| runtime.to_string())
+                    .collect()
+            });
+        };
+
+        let directory = match std::env::current_dir() {
+            Ok(directory) => directory.to_string_lossy().into_owned(),
+            Err(error) => {
+                format_error(format!("Failed to get the current directory: {}",
+                    error).as_str(), true, "container");
+                process::exit(-1);
+            }
+        };
+
+        // the working directory is mounted at the same path, so a command's
+        // `cwd` means the same thing inside of the container
+        let working_directory = match &command.cwd {
+            Some(cwd) => Path::new(&directory).join(cwd),
+            None => PathBuf::from(&directory)
+        };
+
+        let mut cmd = process::Command::new(runtime);
+        cmd.args(["run", "--rm", "-i"])
+            .arg("-v")
+            .arg(format!("{}:{}", directory, directory))
+            .arg("-w")
+            .arg(working_directory);
+
+        // coyote's own variables (such as COYOTE_OFFLINE) are passed through,
+        // but the rest of the host environment is not
+        for (key, _) in std::env::vars_os() {
+            let key = key.to_string_lossy();
+            if key.starts_with("COYOTE_") {
+                cmd.arg("-e").arg(key.as_ref());
+            }
+        }
+
+        // later values take precedence, so the command's own `env` comes last
+        for (key, value) in self.env.iter().chain(command.env.iter().flatten()) {
+            cmd.arg("-e").arg(format!("{}={}", key, value));
+        }
+
+        cmd.arg(image);
+        match self.shell_for(command) {
+            Some(shell) => cmd.args(shell).arg(command.command_line()),
+            None => cmd.arg(&command.command).args(&command.arguments)
+        };
+        Ok(cmd)
+    }
+
+    /// The shell a command runs through, unless it opts out of the top level
+    /// `shell`
+    fn shell_for(&self, command: &Command) -> Option<&[String]> {
+        self.shell
+            .as_deref()
+            .filter(|_| command.shell != Some(false))
+    }
+
+    /// Resolves the program of every command up front, so that a missing tool
+    /// is reported before any work begins
+    fn check_programs(&mut self, executables: &[Executable]) {
+        let mut missing: Vec<String> = Vec::new();
+        let mut containerized = false;
+
+        for exec in executables {
+            for command in exec.all_commands() {
+                // programs inside containers are not on the host's PATH
+                if exec.container_for(command).is_some() {
+                    containerized = true;
+                    continue;
+                }
+
+                // the shell finds the programs of the commands it runs
+                let search = self.search_path(Some(command));
+                if let Some(shell) = self.shell_for(command) {
+                    let shell = shell[0].clone();
+                    if !missing.contains(&shell) && self
+                        .find_program(&shell, search.as_deref())
+                        .is_none() {
+                        missing.push(shell);
+                    }
+                    continue;
+                }
+
+                // programs built from captured variables can only be resolved
+                // once the target is building
+                if command.command.contains('{')
+                    || missing.contains(&command.command) {
+                    continue;
+                }
+
+                if command.fallback.is_some() {
+                    if let Err(tried) = self.resolve_command(command) {
+                        missing.push(format!("{} (or {})", command.command,
+                            tried[1..].join(", ")));
+                    }
+                } else if self
+                    .find_program(&command.command, search.as_deref())
+                    .is_none() {
+                    missing.push(command.command.clone());
+                }
+            }
+        }
+
+        if containerized && self.container_runtime().is_none() {
+            missing.push(self.container_runtime
+                .clone()
+                .unwrap_or_else(|| CONTAINER_RUNTIMES.join(" or ")));
+        }
+
+        if !missing.is_empty() {
+            format_error(format!("Cannot find the following programs on \
+                PATH: {}", missing.join(", ")).as_str(), true, "programs");
+        }
+    }
+
+    /// Writes the progress of the build so far to coyote.LOCK, keeping the
+    /// timestamps from before the build so that an interrupted build does
+    /// not look up to date
+    fn checkpoint(&mut self) {
+        let modified = std::mem::replace(&mut self.last_modified,
+            self.checkpoint_modified.clone());
+        let hash = std::mem::replace(&mut self.last_hash,
+            self.checkpoint_hash.clone());
+        write_lock(self);
+        self.last_modified = modified;
+        self.last_hash = hash;
+    }
+
+    /// Remembers whether a command failed, so that `--retry-failed` and
+    /// `--resume` know what is left to do
+    fn record(&mut self, id: String, failed: bool) {
+        self.last_failed.retain(|failed_id| failed_id != &id);
+        if failed {
+            self.last_failed.push(id);
+        } else if !self.completed.contains(&id) {
+            self.completed.push(id);
+            self.checkpoint();
+        }
+    }
+
+    /// Remembers how long a command took if it succeeded, to compare the
+    /// next build against
+    fn record_duration(&mut self, id: &str, result: &CommandResult) {
+        if result.status == CommandStatus::Succeeded {
+            self.durations.insert(id.to_string(), result.duration_ms as u64);
+        }
+    }
+
+    fn run_settings(&self) -> RunSettings {
+        RunSettings {
+            transient_exit_codes: self.transient_exit_codes.clone(),
+            transient_retries: self.transient_retries,
+            deadline: self.deadline,
+            output_slots: self.max_inflight_output
+                .map(|slots| Arc::new(Mutex::new(slots)))
+        }
+    }
+}
+
+impl CoyoteJson {
+    fn preprocess(&mut self) {
+        // firstly, preprocess all of the variable declarations (eg. inserting
+        // variable references where $<name> is present, etc.)
+        let variables = resolve_variables(&self.variables, HashMap::new());
+        self.resolved_variables = variables.clone();
+
+        // environment values can reference variables (and run backtick
+        // commands) just like variables themselves
+        self.resolved_env = self.env
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.clone(), check_var_string(
+                patch_string(value, &variables, None),
+                key.clone()
+            )))
+            .collect();
+
+        // go through all commands and fill in all strings with preprocessing
+        // data. Variables captured from commands are only known while the
+        // target is building, so references to them are left for later
+        for exec in &mut self.executables {
+            // target variables are layered on top of the global ones, along
+            // with the built-in `{target}` (unless a variable of that name is
+            // declared, which takes precedence)
+            let mut scoped = variables.clone();
+            scoped.entry("target".to_string())
+                .or_insert_with(|| exec.target.clone());
+
+            exec.resolved_variables = match &exec.variables {
+                Some(local) => resolve_variables(local, scoped),
+                None => scoped
+            };
+            let variables = &exec.resolved_variables;
+
+            let captured = exec.captured_variables();
+            let deferred = if captured.is_empty() {
+                None
+            } else {
+                Some(&captured)
+            };
+
+            let hooks = exec.on_failure.iter_mut().flatten();
+            for command in exec.commands.iter_mut().chain(hooks) {
+                command.patch_references(&exec.target, variables, deferred);
+
+                // inline stdin is substituted once up front, as it may also
+                // contain backtick commands
+                if let Some(stdin) = &command.stdin {
+                    command.stdin = Some(check_var_string(
+                        patch_string(stdin, variables,
+                            command.cwd.as_deref()),
+                        stdin.clone()
+                    ));
+                }
+
+                // as are both the names and values of environment variables
+                if let Some(env) = &command.env {
+                    let patch = |value: &String| check_var_string(
+                        patch_string(value, variables, command.cwd.as_deref()),
+                        value.clone()
+                    );
+
+                    command.env = Some(env
+                        .iter()
+                        .map(|(key, value)| (patch(key), patch(value)))
+                        .collect());
+                }
+            }
+        }
+    }
+
+    /// Writes the variables listed in `generate_header` as `#define`s. The
+    /// file is left untouched if it is already up to date, so that it does
+    /// not look modified to `run_if` conditions
+    fn generate_header(&self) {
+        let Some(header) = &self.generate_header else {
+            return;
+        };
+
+        let mut contents = String::from("/* generated by coyote, do not edit */\n\
+            #pragma once\n\n");
+
+        for name in &header.vars {
+            let Some(value) = self.resolved_variables.get(name) else {
+                format_error(format!("Unknown variable '{}' in generate_header",
+                    name).as_str(), true, "header");
+                process::exit(-1);
+            };
+            let value = join_list(value, " ");
+
+            // numbers are emitted as-is, anything else as a string literal
+            let numeric = value.parse::<i64>().is_ok()
+                || value.parse::<f64>().is_ok_and(|n| n.is_finite());
+            let value = if numeric {
+                value.into_owned()
+            } else {
+                format!("\"{}\"", value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\""))
+            };
+
+            contents.push_str(format!("#define {} {}\n", name, value).as_str());
+        }
+
+        if fs::read_to_string(&header.path).is_ok_and(|old| old == contents) {
+            return;
+        }
+
+        if let Err(error) = fs::write(&header.path, contents) {
+            format_error(format!("Failed to write '{}': {}", header.path,
+                error).as_str(), true, "header");
+        }
+    }
+
+    /// Prints a JSON listing of the targets for tooling. This is called before
+    /// preprocessing, so no backtick commands are run
+    fn print_targets_json(&self) {
+        let targets: Vec<TargetInfo> = self.executables
+            .iter()
+            .map(|exec| TargetInfo {
+                name: &exec.target,
+                description: exec.description.as_deref(),
+                command_count: exec.commands.len(),
+                depends: exec.depends
+                    .iter()
+                    .flatten()
+                    .map(|target| target.as_str())
+                    .collect(),
+                extends: exec.extends.as_deref()
+            })
+            .collect();
+
+        match serde_json::to_string_pretty(&targets) {
+            Ok(json) => println!("{}", json),
+            Err(error) => format_error(format!("Failed to list targets: {}",
+                error).as_str(), true, "print-targets-json")
+        }
+    }
+
+    /// The build as a DOT graph. Each target is a cluster of its commands,
+    /// chained in the order they run unless the target is `parallel`. Solid
+    /// edges between clusters are `depends`, and dashed edges go from a
+    /// command that writes one of its `outputs` to the commands that read it
+    fn plan_dot(&self) -> String {
+        // a target without commands still needs a node for its edges
+        let node = |exec: &Executable, index: usize| if exec.commands
+            .is_empty() {
+            dot_quoted(&exec.target)
+        } else {
+            dot_quoted(&format!("{}:{}", exec.target, index))
+        };
+
+        let mut dot = vec!["digraph plan {".to_string(),
+            "    compound=true;".to_string(),
+            "    node [shape=box];".to_string()];
+
+        for (cluster, exec) in self.executables.iter().enumerate() {
+            let label = if exec.parallel == Some(true) {
+                format!("{} (parallel)", exec.target)
+            } else {
+                exec.target.clone()
+            };
+            dot.push(format!("    subgraph cluster_{} {{", cluster));
+            dot.push(format!("        label={};", dot_quoted(&label)));
+
+            if exec.commands.is_empty() {
+                dot.push(format!("        {} [shape=point];", node(exec, 0)));
+            }
+            for (index, command) in exec.commands.iter().enumerate() {
+                dot.push(format!("        {} [label={}];", node(exec, index),
+                    dot_quoted(&exec.shown(command).command_line())));
+            }
+
+            if exec.parallel != Some(true) {
+                for index in 1..exec.commands.len() {
+                    dot.push(format!("        {} -> {};",
+                        node(exec, index - 1), node(exec, index)));
+                }
+            }
+            dot.push("    }".to_string());
+        }
+
+        for (cluster, exec) in self.executables.iter().enumerate() {
+            for dependency in exec.depends.iter().flatten() {
+                let Some((dep_cluster, dep_exec)) = self.executables
+                    .iter()
+                    .enumerate()
+                    .find(|(_, dep_exec)| &dep_exec.target == dependency)
+                else {
+                    continue;
+                };
+
+                let last = dep_exec.commands.len().saturating_sub(1);
+                dot.push(format!("    {} -> {} [ltail=cluster_{}, \
+                    lhead=cluster_{}];", node(dep_exec, last), node(exec, 0),
+                    dep_cluster, cluster));
+            }
+        }
+
+        for exec in &self.executables {
+            for (index, command) in exec.commands.iter().enumerate() {
+                for path in command.input_paths() {
+                    for writer in &self.executables {
+                        for (writer_index, _) in writer.commands
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, writer_command)| writer_command.outputs
+                                .iter()
+                                .flatten()
+                                .any(|output| output == path)) {
+                            dot.push(format!("    {} -> {} [style=dashed, \
+                                label={}];", node(writer, writer_index),
+                                node(exec, index), dot_quoted(path)));
+                        }
+                    }
+                }
+            }
+        }
+
+        dot.push("}".to_string());
+        dot.join("\n")
+    }
+
+    /// The declared (global and target) variables that are never referenced.
+    /// Only meaningful after preprocessing, which records the references made
+    /// by commands and other variables. `if` expressions are only evaluated
+    /// while building, so their references are collected here
+    fn unused_variables(&self) -> Vec<String> {
+        for exec in &self.executables {
+            // captured variables have no value yet, but still count as known
+            let mut variables = exec.resolved_variables.clone();
+            for name in exec.captured_variables() {
+                variables.entry(name).or_default();
+            }
+
+            let expressions = exec.if_expression
+                .iter()
+                .chain(exec.all_commands()
+                    .filter_map(|command| command.if_expression.as_ref()));
+            for expression in expressions {
+                let _ = tokenize_expression(expression, &variables);
+            }
+        }
+
+        let mut used = USED_VARIABLES.lock()
+            .map(|used| used.clone())
+            .unwrap_or_default();
+        used.extend(self.generate_header
+            .iter()
+            .flat_map(|header| header.vars.iter().cloned()));
+
+        let declared = [&self.variables]
+            .into_iter()
+            .chain(self.executables
+                .iter()
+                .filter_map(|exec| exec.variables.as_ref()))
+            .filter_map(|declarations| declarations.as_object())
+            .flat_map(|declarations| declarations.keys());
+
+        declared
+            .filter(|name| !used.contains(*name))
+            .cloned()
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Prints every target with its number of commands, followed by the recipes
+    /// that can be built from the current directory
+    fn list(&self) {
+        say!("[coyote] {} '{}'", style("Targets in project").cyan(),
+            self.project_name);
+        for exec in &self.executables {
+            let commands = format!("({} command(s))", exec.commands.len());
+            match &exec.description {
+                Some(description) => say!("    {} {} - {}", exec.target,
+                    style(commands).color256(8), description),
+                None => say!("    {} {}", exec.target, style(commands).color256(8))
+            }
+        }
+
+        let recipes = find_recipes();
+        if recipes.is_empty() {
+            say!("[coyote] {}", style("No recipes found").yellow());
+            return;
+        }
+
+        say!("[coyote] {}", style("Recipes").cyan());
+        for recipe in recipes {
+            say!("    {}", recipe);
+        }
+    }
+
+    /// Writes the preprocessed build file to `path`. Variables are written with
+    /// their final values, and targets only keep the variables that differ
+    /// from the global ones. Inherited commands are already part of each
+    /// target, so `extends` is left out
+    fn dump_resolved(&self, path: &str) {
+        let mut resolved = match serde_json::to_value(self) {
+            Ok(resolved) => resolved,
+            Err(error) => {
+                format_error(format!("Failed to serialize the build file: {}",
+                    error).as_str(), true, "dump-resolved");
+                process::exit(-1);
+            }
+        };
+
+        let global: BTreeMap<&String, serde_json::Value> = self.resolved_variables
+            .iter()
+            .map(|(name, value)| (name, variable_json(value)))
+            .collect();
+        resolved["variables"] = serde_json::json!(global);
+        if self.env.is_some() {
+            resolved["env"] = serde_json::json!(self.resolved_env);
+        }
+
+        let targets = resolved["executables"].as_array_mut().unwrap();
+        for (exec, target) in self.executables.iter().zip(targets) {
+            let local: BTreeMap<&String, serde_json::Value> = exec
+                .resolved_variables
+                .iter()
+                .filter(|(name, value)| match self.resolved_variables
+                    .get(*name) {
+                    Some(global) => global != *value,
+                    None => !(*name == "target" && **value == exec.target)
+                })
+                .map(|(name, value)| (name, variable_json(value)))
+                .collect();
+
+            let target = target.as_object_mut().unwrap();
+            target.remove("extends");
+            if !exec.captured_variables().is_empty() {
+                let shown = |commands: &[Command]| serde_json::json!(commands
+                    .iter()
+                    .map(|command| command.unescaped())
+                    .collect::<Vec<Command>>());
+                target.insert("commands".to_string(), shown(&exec.commands));
+                if let Some(hooks) = &exec.on_failure {
+                    target.insert("on_failure".to_string(), shown(hooks));
+                }
+            }
+            if local.is_empty() {
+                target.remove("variables");
+            } else {
+                target.insert("variables".to_string(), serde_json::json!(local));
+            }
+        }
+
+        let written = serde_json::to_string_pretty(&resolved)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json + "\n")
+                .map_err(|e| e.to_string()));
+
+        if let Err(error) = written {
+            format_error(format!("Failed to write resolved build file to \
+                '{}': {}", path, error).as_str(), true, "dump-resolved");
+        }
+    }
+
+    /// Reports every input file that does not exist, grouped by command, and
+    /// returns whether they all do
+    fn verify_inputs(&self) -> bool {
+        let mut checked = 0;
+        let mut missing = 0;
+
+        for exec in &self.executables {
+            for (index, command) in exec.commands.iter().enumerate() {
+                let command = &exec.shown(command);
+                let paths: Vec<&str> = command
+                    .input_paths()
+                    .into_iter()
+                    .inspect(|_| checked += 1)
+                    .filter(|path| fs::metadata(path).is_err())
+                    .collect();
+
+                if paths.is_empty() {
+                    continue;
+                }
+
+                say!("[{}:{}] {}", exec.target, index, command);
+                for path in &paths {
+                    say!("    {} {}", style("missing").red(), path);
+                }
+                missing += paths.len();
+            }
+        }
+
+        if missing == 0 {
+            say!("{}", style(format!("[coyote] All {} input(s) exist",
+                checked)).green());
+        } else {
+            print_error(format!("{} of {} input(s) are missing", missing,
+                checked).as_str(), true, "verify-inputs");
+        }
+
+        missing == 0
+    }
+
+    /// Prints a single command, addressed as `<target>:<index>`. Only that
+    /// target is preprocessed, so backtick commands elsewhere in the build
+    /// file (and in the top level `env`, which isn't printed) don't run
+    fn print_command(&mut self, path: &str, json: bool) {
+        // command paths are formatted as <target>:<index>, where the target
+        // name itself may contain colons
+        let (target, index) = match path.rsplit_once(':') {
+            Some((target, index)) => match index.parse::<usize>() {
+                Ok(index) => (target, index),
+                Err(_) => {
+                    format_error(format!("Invalid command index '{}' in '{}'",
+                        index, path).as_str(), true, "print-command");
+                    process::exit(-1);
+                }
+            },
+            None => {
+                format_error(format!("Command path '{}' must be formatted as \
+                    <target>:<index>", path).as_str(), true, "print-command");
+                process::exit(-1);
+            }
+        };
+
+        self.find_executable(target, "print-command");
+        self.executables.retain(|exec| exec.target == target);
+        self.env = None;
+        self.preprocess();
+
+        let exec = &self.executables[0];
+        let command = &exec.shown(exec.command_at(index, "print-command"));
+
+        let argv = command.argv();
+        let output = if json {
+            serde_json::to_string(&argv).ok()
+        } else {
+            shlex::try_join(argv.iter().map(|arg| arg.as_str())).ok()
+        };
+
+        match output {
+            Some(line) => println!("{}", line),
+            None => format_error(format!("Failed to format command '{}'",
+                command).as_str(), true, "print-command")
+        }
+    }
+
+    fn dump_env(&self, scope: &str) {
+        // the scope is either empty (every command), a target name, or a
+        // <target>:<index> command path
+        let mut commands: Vec<(&Executable, usize)> = Vec::new();
+
+        if scope.is_empty() {
+            for exec in &self.executables {
+                commands.extend((0..exec.commands.len()).map(|i| (exec, i)));
+            }
+        } else if let Some(exec) = self.executables
+            .iter()
+            .find(|exec| exec.target == scope) {
+            commands.extend((0..exec.commands.len()).map(|i| (exec, i)));
+        } else {
+            let (target, index) = match scope.rsplit_once(':') {
+                Some((target, index)) => (target, index.parse::<usize>().ok()),
+                None => (scope, None)
+            };
+
+            let exec = self.find_executable(target, "dump-env");
+            match index {
+                Some(index) => {
+                    exec.command_at(index, "dump-env");
+                    commands.push((exec, index));
+                },
+                None => {
+                    format_error(format!("No target or command named '{}'",
+                        scope).as_str(), true, "dump-env");
+                }
+            }
+        }
+
+        for (exec, index) in commands {
+            let command = &exec.shown(&exec.commands[index]);
+            println!("[{}:{}] {}", exec.target, index, command);
+
+            for (key, value) in command.environment(&self.resolved_env) {
+                println!("    {}={}", key, value);
+            }
+        }
+    }
+
+    /// Finds the target marked with `entry`, if there is one. At most one
+    /// target may be marked as the entry
+    fn entry_target(&self) -> Option<String> {
+        let entries: Vec<&str> = self.executables
+            .iter()
+            .filter(|exec| exec.entry == Some(true))
+            .map(|exec| exec.target.as_str())
+            .collect();
+
+        if entries.len() > 1 {
+            format_error(format!("Only one target may be marked as the entry, \
+                but found {} ('{}')",
+                entries.len(),
+                entries.join("', '")).as_str(), true, "entry");
+        }
+
+        entries.first().map(|target| target.to_string())
+    }
+
+    /// Sets variables given on the command line, in place of the global and
+    /// target variables of the same name
+    fn override_variables(&mut self, overrides: &[(String, String)]) {
+        let scopes = std::iter::once(&mut self.variables)
+            .chain(self.executables
+                .iter_mut()
+                .filter_map(|exec| exec.variables.as_mut()));
+
+        for (index, scope) in scopes.enumerate() {
+            let Some(variables) = scope.as_object_mut() else {
+                continue;
+            };
+
+            // new variables go in the global scope, while a target's own
+            // version of one is replaced so that it can't shadow the override.
+            // Values are taken literally, so braces and backticks are escaped
+            // rather than substituted
+            for (name, value) in overrides {
+                if index == 0 || variables.contains_key(name) {
+                    let literal = value.replace('{', "{{").replace('`', "``");
+                    variables.insert(name.clone(), literal.into());
+                }
+            }
+        }
+    }
+
+    /// Merges the executables and variables of every included build file (and
+    /// the files those include) into this one, which was loaded from `path`.
+    /// Variables that are already declared keep their value
+    fn resolve_includes(&mut self, path: &str) {
+        let mut sources: HashMap<String, String> = self.executables
+            .iter()
+            .map(|exec| (exec.target.clone(), path.to_string()))
+            .collect();
+        let mut including: Vec<PathBuf> =
+            fs::canonicalize(path).into_iter().collect();
+        let mut included: HashSet<PathBuf> = HashSet::new();
+
+        let includes = self.include.take().unwrap_or_default();
+        self.merge_includes(includes, path, &mut including, &mut included,
+            &mut sources);
+    }
+
+    fn merge_includes(
+        &mut self,
+        includes: Vec<String>,
+        from: &str,
+        including: &mut Vec<PathBuf>,
+        included: &mut HashSet<PathBuf>,
+        sources: &mut HashMap<String, String>) {
+        // a remote build file can only include local files relative to the
+        // current directory
+        let base = match Path::new(from).parent() {
+            Some(parent) if !from.contains("://") => parent,
+            _ => Path::new(".")
+        };
+
+        for include in includes {
+            let path = base.join(&include).to_string_lossy().into_owned();
+            let contents = fs::read_to_string(&path);
+            let (Ok(canonical), Ok(contents)) = (fs::canonicalize(&path),
+                contents) else {
+                format_error(format!("Failed to read '{}', included by '{}'",
+                    path, from).as_str(), true, "include");
+                process::exit(-1);
+            };
+
+            if including.contains(&canonical) {
+                format_error(format!("'{}' includes '{}', which includes it \
+                    back", from, path).as_str(), true, "include");
+            }
+
+            // a file included more than once (eg. by two subprojects) is
+            // only merged the first time
+            if !included.insert(canonical.clone()) {
+                continue;
+            }
+            self.included.push(path.clone());
+
+            let config = parse_config(&contents, &path);
+            match (self.variables.as_object_mut(), config.variables) {
+                (Some(variables), serde_json::Value::Object(extra)) => {
+                    for (name, value) in extra {
+                        variables.entry(name).or_insert(value);
+                    }
+                },
+                _ => format_error(format!("The variables of '{}' and '{}' \
+                    must both be objects to include one in the other", from,
+                    path).as_str(), true, "include")
+            }
+
+            for exec in config.executables {
+                if let Some(other) = sources.insert(exec.target.clone(),
+                    path.clone()) {
+                    format_error(format!("Target '{}' is defined in both '{}' \
+                        and '{}'", exec.target, other, path).as_str(), true,
+                        "include");
+                }
+                self.executables.push(exec);
+            }
+
+            including.push(canonical);
+            self.merge_includes(config.include.unwrap_or_default(), &path,
+                including, included, sources);
+            including.pop();
+        }
+    }
+
+    /// Merges the base target into every target that extends another one.
+    /// The base's commands, `on_failure` hooks and `depends` come first,
+    /// followed by the target's own. `retry`, `container` and `parallel` are
+    /// only inherited if the target doesn't set them, and variables are
+    /// merged, with the target's own taking the place of the base's
+    fn resolve_extends(&mut self) {
+        let resolved: Vec<Executable> = self.executables
+            .iter()
+            .map(|exec| self.inherited(exec, &mut Vec::new()))
+            .collect();
+
+        self.executables = resolved;
+    }
+
+    fn inherited(&self, exec: &Executable, chain: &mut Vec<String>)
+        -> Executable {
+        if chain.contains(&exec.target) {
+            chain.push(exec.target.clone());
+            format_error(format!("Cyclic 'extends' detected: {}",
+                chain.join(" -> ")).as_str(), true, "extends");
+        }
+
+        let mut resolved = exec.clone();
+        let Some(base) = &exec.extends else {
+            return resolved;
+        };
+
+        let base_exec = match self.executables
+            .iter()
+            .find(|base_exec| &base_exec.target == base) {
+            Some(base_exec) => base_exec,
+            None => {
+                format_error(format!("Target '{}' extends '{}' which does not \
+                    exist", exec.target, base).as_str(), true, "extends");
+                process::exit(-1);
+            }
+        };
+
+        chain.push(exec.target.clone());
+        let base = self.inherited(base_exec, chain);
+        chain.pop();
+
+        resolved.commands = base.commands;
+        resolved.commands.extend(exec.commands.iter().cloned());
+
+        resolved.on_failure = match (base.on_failure, &exec.on_failure) {
+            (Some(mut hooks), Some(own)) => {
+                hooks.extend(own.iter().cloned());
+                Some(hooks)
+            },
+            (hooks, own) => own.clone().or(hooks)
+        };
+
+        resolved.depends = match (base.depends, &exec.depends) {
+            (Some(mut depends), Some(own)) => {
+                depends.extend(own
+                    .iter()
+                    .filter(|target| !depends.contains(target))
+                    .cloned()
+                    .collect::<Vec<String>>());
+                Some(depends)
+            },
+            (depends, own) => own.clone().or(depends)
+        };
+
+        resolved.variables = match (base.variables, &exec.variables) {
+            (Some(serde_json::Value::Object(mut variables)),
+                Some(serde_json::Value::Object(own))) => {
+                variables.extend(own.clone());
+                Some(serde_json::Value::Object(variables))
+            },
+            (variables, own) => own.clone().or(variables)
+        };
+
+        resolved.retry = exec.retry.clone().or(base.retry);
+        resolved.container = exec.container.clone().or(base.container);
+        resolved.parallel = exec.parallel.or(base.parallel);
+        resolved
+    }
+
+    /// Reorders the targets so that every target comes after the targets it
+    /// depends on. Targets keep their relative order otherwise. A target name
+    /// that is defined twice is fatal, as the order is keyed by name
+    fn order_by_depends(&mut self) {
+        let mut targets = HashSet::new();
+        for exec in &self.executables {
+            if !targets.insert(&exec.target) {
+                format_error(format!("Target '{}' is defined more than once",
+                    exec.target).as_str(), true, "depends");
+            }
+        }
+
+        let mut order: Vec<String> = Vec::new();
+        for exec in &self.executables {
+            self.visit_depends(exec, &mut order, &mut Vec::new());
+        }
+
+        let mut executables = std::mem::take(&mut self.executables);
+        for target in order {
+            let index = executables
+                .iter()
+                .position(|exec| exec.target == target)
+                .unwrap();
+            self.executables.push(executables.remove(index));
+        }
+    }
+
+    fn visit_depends(&self, exec: &Executable, order: &mut Vec<String>,
+        chain: &mut Vec<String>) {
+        if order.contains(&exec.target) {
+            return;
+        }
+
+        if chain.contains(&exec.target) {
+            chain.push(exec.target.clone());
+            format_error(format!("Cyclic 'depends' detected: {}",
+                chain.join(" -> ")).as_str(), true, "depends");
+        }
+
+        chain.push(exec.target.clone());
+        for dependency in exec.depends.iter().flatten() {
+            match self.executables
+                .iter()
+                .find(|dep_exec| &dep_exec.target == dependency) {
+                Some(dep_exec) => self.visit_depends(dep_exec, order, chain),
+                None => format_error(format!("Target '{}' depends on '{}' \
+                    which does not exist", exec.target, dependency).as_str(),
+                    true, "depends")
+            }
+        }
+        chain.pop();
+
+        order.push(exec.target.clone());
+    }
+
+    /// The given target and every target it (transitively) depends on
+    fn with_depends(&self, target: &str) -> HashSet<String> {
+        let mut targets = HashSet::new();
+        let mut pending = vec![target.to_string()];
+        while let Some(target) = pending.pop() {
+            if !targets.insert(target.clone()) {
+                continue;
+            }
+
+            if let Some(exec) = self.executables
+                .iter()
+                .find(|exec| exec.target == target) {
+                pending.extend(exec.depends.iter().flatten().cloned());
+            }
+        }
+
+        targets
+    }
+
+    /// Shows a menu of every target and returns the ones that were picked.
+    /// Backing out of the menu stops coyote without building anything
+    fn select_targets(&self) -> Vec<String> {
+        let items: Vec<String> = self.executables
+            .iter()
+            .map(|exec| match &exec.description {
+                Some(description) => format!("{} - {}", exec.target,
+                    description),
+                None => exec.target.clone()
+            })
+            .collect();
+
+        let picked = dialoguer::MultiSelect::new()
+            .with_prompt("Targets to build (space to select, enter to build)")
+            .items(&items)
+            .interact_opt();
+
+        match picked {
+            Ok(Some(picked)) if !picked.is_empty() => picked
+                .into_iter()
+                .map(|index| self.executables[index].target.clone())
+                .collect(),
+            Ok(_) => {
+                say!("[coyote] {}", style("No targets selected").yellow());
+                process::exit(0);
+            },
+            Err(error) => {
+                format_error(format!("Failed to show the target menu: {}",
+                    error).as_str(), true, "interactive");
+                process::exit(-1);
+            }
+        }
+    }
+
+    fn find_executable(&self, target: &str, subname: &str) -> &Executable {
+        match self.executables.iter().find(|exec| exec.target == target) {
+            Some(exec) => exec,
+            None => {
+                format_error(format!("No target named '{}'", target).as_str(),
+                    true, subname);
+                process::exit(-1);
+            }
+        }
+    }
+}
+
+impl ConditionToken {
+    fn patched(&self, patch: &impl Fn(&String) -> Result<String, String>)
+        -> Result<Self, String> {
+        Ok(match self {
+            ConditionToken::Word(word) => ConditionToken::Word(patch(word)?),
+            ConditionToken::Condition(condition) => ConditionToken::Condition(
+                condition
+                    .iter()
+                    .map(|token| token.patched(patch))
+                    .collect::<Result<_, _>>()?)
+        })
+    }
+}
+
+/// Collects the files that a `run_if` condition (and any conditions it
+/// combines) reads
+fn condition_paths<'a>(cond: &'a [ConditionToken], kinds: &[&str],
+    paths: &mut Vec<&'a str>) {
+    let mut words = Vec::new();
+    for token in cond {
+        match token {
+            ConditionToken::Word(word) => words.push(word.as_str()),
+            ConditionToken::Condition(condition) =>
+                condition_paths(condition, kinds, paths)
+        }
+    }
+
+    // negating a condition doesn't change what it reads
+    let start = words.iter().take_while(|word| **word == "!").count();
+    if let [kind, path, ..] = words[start..] {
+        if kinds.contains(&kind) {
+            paths.push(path);
+        }
+    }
+}
+
+/// Splits a glob pattern into the directory it searches (the part before
+/// the first component with a wildcard) and the rest of the pattern
+fn glob_base(pattern: &str) -> (&str, &str) {
+    let wildcard = pattern.find(['*', '?', '[']).unwrap_or(pattern.len());
+    match pattern[..wildcard].rfind('/') {
+        Some(0) => ("/", &pattern[1..]),
+        Some(slash) => (&pattern[..slash], &pattern[slash + 1..]),
+        None => (".", pattern)
+    }
+}
+
+impl Command {
+    fn patch_references(
+        &mut self,
+        target: &str,
+        variables: &HashMap<String, String>,
+        deferred: Option<&HashSet<String>>)
+    {
+        let patch = |value: &String| check_var_string(
+            patch_variable_references(value, variables, deferred),
+            value.clone()
+        );
+
+        self.command = patch(&self.command);
+
+        // loop through arguments, expanding any brace expressions, and patch
+        // them. An argument that is just a reference to a list variable
+        // becomes one argument per item
+        self.arguments = self.arguments
+            .iter()
+            .flat_map(|argument| expand_braces(argument))
+            .flat_map(|argument| {
+                let items = argument
+                    .strip_prefix('{')
+                    .and_then(|name| name.strip_suffix('}'))
+                    .and_then(|name| variables.get(name))
+                    .and_then(|value| list_items(value));
+
+                match items {
+                    Some(items) => {
+                        patch(&argument);
+                        items.into_iter().map(String::from).collect()
+                    },
+                    None => vec![patch(&argument)]
+                }
+            })
+            .collect();
+
+        // an undefined reference in a run_if is reported as coming from one,
+        // since the condition isn't shown anywhere else
+        if let Err(reference) = self.patch_run_if(variables, deferred) {
+            format_error(format!("'run_if' of command '{}' in target '{}' \
+                references '{}' which is not defined", self.command, target,
+                reference).as_str(), true, "run_if");
+        }
+
+        // file paths may also reference variables
+        for path in [
+            &mut self.golden,
+            &mut self.golden_actual,
+            &mut self.stdin_file,
+            &mut self.cwd
+        ]
+            .into_iter()
+            .flatten() {
+            *path = patch(path);
+        }
+
+        if let Some(outputs) = &self.outputs {
+            self.outputs = Some(outputs.iter().map(patch).collect());
+        }
+    }
+
+    /// Substitutes variables into the `run_if` condition, returning the first
+    /// reference that isn't defined
+    fn patch_run_if(
+        &mut self,
+        variables: &HashMap<String, String>,
+        deferred: Option<&HashSet<String>>) -> Result<(), String> {
+        let Some(run_if) = &self.run_if else {
+            return Ok(());
+        };
+
+        let patch = |value: &String|
+            patch_variable_references(value, variables, deferred);
+        self.run_if = Some(run_if
+            .iter()
+            .map(|token| token.patched(&patch))
+            .collect::<Result<_, _>>()?);
+        Ok(())
+    }
+
+    /// The command as it is shown before the values of its deferred variables
+    /// are known, without the escaping that `patch_references` adds for them
+    fn unescaped(&self) -> Command {
+        let mut command = self.clone();
+        let unescape = |value: &String| unescape_braces(value);
+
+        command.command = unescape(&self.command);
+        command.arguments = self.arguments.iter().map(unescape).collect();
+        command.run_if = self.run_if.as_ref().map(|run_if| run_if
+            .iter()
+            .filter_map(|token| token
+                .patched(&|word| Ok::<_, String>(unescape(word)))
+                .ok())
+            .collect());
+
+        for path in [
+            &mut command.golden,
+            &mut command.golden_actual,
+            &mut command.stdin_file,
+            &mut command.cwd
+        ]
+            .into_iter()
+            .flatten() {
+            *path = unescape(path);
+        }
+
+        command.outputs = self.outputs
+            .as_ref()
+            .map(|outputs| outputs.iter().map(unescape).collect());
+        command
+    }
+
+    /// The environment the command runs with: the one coyote inherits, with
+    /// the top level `env` set on top of it, and the command's own `env` on
+    /// top of that
+    fn environment(&self, global: &BTreeMap<String, String>)
+        -> Vec<(String, String)> {
+        let mut environment: BTreeMap<String, String> = std::env::vars_os()
+            .map(|(key, value)| (
+                key.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned()
+            ))
+            .collect();
+
+        environment.extend(global.clone());
+        environment.extend(self.env.clone().unwrap_or_default());
+        environment.into_iter().collect()
+    }
+
+    /// The content to pipe into the command's stdin, if it has any
+    fn stdin_content(&self) -> Option<Vec<u8>> {
+        if self.stdin.is_some() && self.stdin_file.is_some() {
+            format_error(format!("Command '{}' cannot specify both 'stdin' \
+                and 'stdin_file'", self).as_str(), true, "stdin");
+        }
+
+        if let Some(path) = &self.stdin_file {
+            match fs::read(path) {
+                Ok(content) => Some(content),
+                Err(_) => {
+                    format_error(format!("Cannot read stdin file '{}'", path)
+                        .as_str(), true, "stdin");
+                    process::exit(-1);
+                }
+            }
+        } else {
+            self.stdin.as_ref().map(|stdin| stdin.as_bytes().to_vec())
+        }
+    }
+
+    /// Files that coyote itself reads for this command, from file based
+    /// `run_if` conditions and `stdin_file`
+    fn input_paths(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = Vec::new();
+
+        if let Some(condition) = &self.run_if {
+            condition_paths(condition,
+                &["modified", "not_modified", "hash", "contains"], &mut paths);
+        }
+
+        if let Some(path) = &self.stdin_file {
+            paths.push(path);
+        }
+
+        paths
+    }
+
+    /// The patterns of the command's `glob` conditions
+    fn glob_patterns(&self) -> Vec<&str> {
+        let mut patterns: Vec<&str> = Vec::new();
+        if let Some(condition) = &self.run_if {
+            condition_paths(condition, &["glob"], &mut patterns);
+        }
+        patterns
+    }
+
+    fn argv(&self) -> Vec<String> {
+        let mut argv = vec![self.command.clone()];
+        argv.extend(self.arguments.iter().cloned());
+        argv
+    }
+
+    /// The first output that is missing or empty, if the command requires its
+    /// outputs
+    fn missing_output(&self) -> Option<&str> {
+        if self.require_outputs != Some(true) {
+            return None;
+        }
+
+        self.outputs
+            .iter()
+            .flatten()
+            .find(|output| fs::metadata(output)
+                .map_or(true, |metadata| metadata.len() == 0))
+            .map(String::as_str)
+    }
+
+    /// The command and its arguments as a single line, for running through a
+    /// shell. The command is used as it is written (so that it can use pipes
+    /// and the like), while every argument is quoted to stay a single word
+    fn command_line(&self) -> String {
+        let arguments = self.arguments.iter().map(|argument| {
+            shlex::try_quote(argument)
+                .map_or_else(|_| argument.clone(), |quoted| quoted.into_owned())
+        });
+
+        std::iter::once(self.command.clone())
+            .chain(arguments)
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    fn merges_stderr(&self) -> bool {
+        self.merge_stderr == Some(true)
+    }
+
+    /// Streaming is turned off by `--format json`, which can't have command
+    /// output mixed into it
+    fn streams(&self) -> bool {
+        self.stream == Some(true) && !json_output()
+    }
+
+    /// The lines of streamed output to show, from `output_filter` and
+    /// `output_exclude`
+    fn line_filter(&self) -> LineFilter {
+        let compile = |pattern: &Option<String>, field: &str| pattern
+            .as_ref()
+            .map(|pattern| Regex::new(pattern).unwrap_or_else(|error| {
+                format_error(format!("Invalid '{}' of command '{}': {}", field,
+                    self, error).as_str(), true, "output");
+                process::exit(-1);
+            }));
+
+        LineFilter {
+            include: compile(&self.output_filter, "output_filter"),
+            exclude: compile(&self.output_exclude, "output_exclude")
+        }
+    }
+
+    /// The output to show when the command fails, which is stdout when stderr
+    /// was merged into it. Streamed output has already been shown
+    fn error_output(&self, output: &process::Output) -> String {
+        if self.streams() {
+            return String::new();
+        }
+
+        let error_output = if self.merges_stderr() {
+            &output.stdout
+        } else {
+            &output.stderr
+        };
+
+        String::from_utf8_lossy(error_output).into_owned()
+    }
+
+    fn requests_stop(&self, output: &process::Output) -> bool {
+        let code_matches = match (self.stop_code, output.status.code()) {
+            (Some(stop_code), Some(code)) => stop_code == code,
+            _ => false
+        };
+
+        let marker_found = match &self.stop_marker {
+            Some(marker) => String::from_utf8_lossy(&output.stdout)
+                .contains(marker.as_str()),
+            None => false
+        };
+
+        code_matches || marker_found
+    }
+
+    /// Compares the captured stdout of the command against the contents of
+    /// its golden file, returning a unified diff if they differ
+    fn golden_mismatch(&self, golden: &str, stdout: &[u8]) -> Option<String> {
+        let expected = match fs::read_to_string(golden) {
+            Ok(x) => x,
+            Err(_) => {
+                format_error(format!("Cannot read golden file '{}'", golden)
+                    .as_str(), true, "golden");
+                process::exit(-1);
+            }
+        };
+        let actual = String::from_utf8_lossy(stdout);
+
+        if expected == actual {
+            return None;
+        }
+
+        // keep the actual output around for inspection (or for updating the
+        // golden file)
+        if let Some(path) = &self.golden_actual {
+            if fs::write(path, actual.as_bytes()).is_err() {
+                format_error(format!("Failed to write actual output to '{}'",
+                    path).as_str(), true, "golden");
+            }
+        }
+
+        Some(TextDiff::from_lines(expected.as_str(), actual.as_ref())
+            .unified_diff()
+            .header(golden, "actual")
+            .to_string())
+    }
+}
+
+impl fmt::Display for CommandStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CommandStatus::Succeeded => "succeeded",
+            CommandStatus::Failed => "failed",
+            CommandStatus::Skipped => "skipped",
+            CommandStatus::Stopped => "stopped"
+        })
+    }
+}
+
+impl RunSettings {
+    fn is_transient(&self, status: process::ExitStatus) -> bool {
+        match status.code() {
+            Some(code) => self.transient_exit_codes.contains(&code),
+            None => false
+        }
+    }
+}
+
+impl CommandResult {
+    fn skipped(command: &Command) -> Self {
+        CommandResult {
+            command: command.to_string(),
+            status: CommandStatus::Skipped,
+            exit_code: None,
+            duration_ms: 0,
+            reason: None
+        }
+    }
+
+    /// A command that failed before it could be run
+    fn failed(command: &Command) -> Self {
+        CommandResult {
+            status: CommandStatus::Failed,
+            reason: Some("could not be started".to_string()),
+            ..CommandResult::skipped(command)
+        }
+    }
+
+    /// Marks a command that ran as failed, with a short reason why
+    fn fail(&mut self, reason: String) {
+        self.status = CommandStatus::Failed;
+        self.reason = Some(reason);
+    }
+}
+
+impl CommandCounts {
+    fn new(results: &[TargetResult]) -> Self {
+        let commands = results.iter().flat_map(|result| &result.commands);
+        let count = |status: CommandStatus| commands
+            .clone()
+            .filter(|command| command.status == status)
+            .count();
+
+        CommandCounts {
+            ran: count(CommandStatus::Succeeded) + count(CommandStatus::Failed)
+                + count(CommandStatus::Stopped),
+            skipped: count(CommandStatus::Skipped),
+            failed: count(CommandStatus::Failed),
+            run_time_ms: commands
+                .clone()
+                .map(|command| command.duration_ms)
+                .sum()
+        }
+    }
+}
+
+impl<'a> BuildSummary<'a> {
+    fn new(
+        project_name: &'a str,
+        outcome: BuildOutcome,
+        started: Instant,
+        results: &'a [TargetResult]) -> Self {
+        BuildSummary {
+            project_name,
+            outcome,
+            duration_ms: started.elapsed().as_millis(),
+            counts: CommandCounts::new(results),
+            targets: results,
+            errors: ERRORS.lock()
+                .map(|errors| errors.clone())
+                .unwrap_or_default()
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns how long to wait before the given retry (counting from 1)
+    fn delay(&self, base_ms: u64, retry: u32) -> Duration {
+        let multiplier = match self {
+            Backoff::Fixed => 1,
+            Backoff::Linear => u64::from(retry),
+            Backoff::Exponential =>
+                1u64.checked_shl(retry.saturating_sub(1)).unwrap_or(u64::MAX)
+        };
+
+        Duration::from_millis(base_ms.saturating_mul(multiplier))
+    }
+}
+
+impl RetryPolicy {
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .unwrap_or(Backoff::Fixed)
+            .delay(self.delay_ms.unwrap_or(0), retry)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.command, self.arguments.join(" "))
+    }
+}
+
+impl Executable {
+    /// Every command of the target, including its `on_failure` hooks
+    fn all_commands(&self) -> impl Iterator<Item = &Command> {
+        self.commands.iter().chain(self.on_failure.iter().flatten())
+    }
+
+    /// A command of this target as it is shown (or written out) before the
+    /// build, when variables captured during the build are still unknown
+    fn shown(&self, command: &Command) -> Command {
+        if self.captured_variables().is_empty() {
+            command.clone()
+        } else {
+            command.unescaped()
+        }
+    }
+
+    /// Runs the target's `on_failure` hooks after one of its commands has
+    /// failed. Hooks ignore `run_if`, and a failing hook does not stop the
+    /// remaining hooks from running
+    fn run_failure_hooks(
+        &self,
+        lock: &mut CoyoteLock,
+        captured: Option<&HashMap<String, String>>)
+    {
+        for command in self.on_failure.iter().flatten() {
+            let mut command = command.clone();
+            if let Some(captured) = captured {
+                command.patch_references(&self.target, captured, None);
+            }
+
+            // a hook whose programs are all missing fails to spawn below
+            let mut cmd = lock
+                .process_for(&command, self.container_for(&command))
+                .unwrap_or_else(|_| process::Command::new(&command.command));
+
+            let output =
+                run_with_stdin(&mut cmd, command.stdin_content().as_deref(),
+                    command.merges_stderr(), None, None);
+            let succeeded = match &output {
+                Ok(output) => output.status.success(),
+                Err(_) => false
+            };
+
+            if !succeeded {
+                let stderr = match &output {
+                    Ok(output) => command.error_output(output),
+                    Err(error) => error.to_string()
+                };
+
+                print_error_with_output(format!("Failed to execute command \
+                    '{}':", command.command).as_str(), &stderr, false,
+                    "on_failure");
+            }
+
+            say!("   {} {} {}",
+                symbol(if succeeded { &GREEN_TICK } else { &RED_CROSS },
+                    lock.ascii),
+                style("Finished on_failure").blue(),
+                command
+            );
+        }
+    }
+
+    /// Names of the variables that commands in this target capture while it
+    /// is being built
+    fn captured_variables(&self) -> HashSet<String> {
+        self.commands
+            .iter()
+            .flat_map(|command| [&command.capture_status, &command.capture])
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    fn command_at(&self, index: usize, subname: &str) -> &Command {
+        match self.commands.get(index) {
+            Some(command) => command,
+            None => {
+                format_error(format!("Target '{}' has no command at index {} \
+                    (it has {} commands)",
+                    self.target,
+                    index,
+                    self.commands.len()).as_str(), true, subname);
+                process::exit(-1);
+            }
+        }
+    }
+
+    /// Returns the retry policy that applies to a command, which is its own
+    /// if it has one and the target's otherwise
+    fn retry_policy<'a>(&'a self, command: &'a Command)
+        -> Option<&'a RetryPolicy> {
+        command.retry.as_ref().or(self.retry.as_ref())
+    }
+
+    /// Returns the container image a command runs in, if any
+    fn container_for<'a>(&'a self, command: &'a Command) -> Option<&'a str> {
+        command.container.as_deref().or(self.container.as_deref())
+    }
+
+    /// Decides whether a command should be skipped. When retrying failed
+    /// commands, only the commands that failed last time are run (regardless
+    /// of `run_if`). The `if` expression is checked first, so that `run_if`
+    /// conditions (which record timestamps) are only checked if it passes
+    fn skip_command(
+        &self,
+        command: &Command,
+        id: &str,
+        lock: &mut CoyoteLock,
+        state: &BuildState,
+        captured: &HashMap<String, String>) -> bool {
+        let enabled = match &command.if_expression {
+            Some(expression) => {
+                let mut variables = self.resolved_variables.clone();
+                variables.extend(captured.clone());
+                check_expression(
+                    expression,
+                    &variables,
+                    format!("target '{}'", self.target).as_str()
+                )
+            },
+            None => true
+        };
+
+        // commands that finished before an interrupted build are skipped
+        // when resuming it
+        let resumed = lock.resume && lock.completed.iter().any(|done| done == id);
+
+        if !enabled || resumed {
+            true
+        } else if lock.retry_failed {
+            !lock.last_failed.iter().any(|failed| failed == id)
+        } else if let Some(outputs) = command.outputs.as_ref().filter(|_| {
+            lock.only_outputs_missing
+                || command.only_outputs_missing == Some(true)
+        }) {
+            // timestamps are ignored entirely, the command only runs to
+            // generate outputs that don't exist
+            !lock.rebuild
+                && outputs.iter().all(|output| fs::metadata(output).is_ok())
+        } else if let Some(condition) = &command.run_if {
+            !lock.rebuild && !run_if_met(condition, &self.target, lock, state)
+        } else {
+            false
+        }
+    }
+
+    /// Builds the process for a command, failing the command if it cannot
+    /// run at all (because it needs the network in `--offline` mode, or its
+    /// program cannot be found)
+    fn spawnable(&self, command: &Command, lock: &mut CoyoteLock)
+        -> Option<process::Command> {
+        if lock.offline && command.requires_network == Some(true) {
+            print_error(format!("Command '{}' in target '{}' requires \
+                network access, which is disabled by --offline",
+                command, self.target).as_str(), true, "offline");
+            return None;
+        }
+
+        // the directory may be created by an earlier command, so it is only
+        // checked right before the command runs
+        if let Some(cwd) = command.cwd.as_ref().filter(|cwd| !Path::new(cwd)
+            .is_dir()) {
+            print_error(format!("Working directory '{}' of command '{}' in \
+                target '{}' does not exist", cwd, command, self.target)
+                .as_str(), true, "cwd");
+            return None;
+        }
+
+        let mut cmd = match lock
+            .process_for(command, self.container_for(command)) {
+            Ok(cmd) => cmd,
+            Err(tried) => {
+                print_error(format!("None of the programs for command '{}' \
+                    in target '{}' could be found (tried {})",
+                    command, self.target, tried.join(", ")).as_str(), true,
+                    "programs");
+                return None;
+            }
+        };
+
+        if let Some(nice) = command.nice.or(lock.nice) {
+            set_nice(&mut cmd, nice);
+        }
+
+        Some(cmd)
+    }
+
+    /// Creates the spinner shown while a command runs. Quiet commands run
+    /// without one, and only report back if something went wrong
+    fn spinner(&self, command: &Command, index: usize) -> ProgressBar {
+        let spinner_style =
+            ProgressStyle::with_template(
+                "{prefix:.bold.dim} {spinner} {wide_msg}"
+            )
+            .unwrap()
+            .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ");
+
+        let pb = if command.quiet == Some(true) || quiet() {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new_spinner()
+        };
+
+        pb.set_style(spinner_style);
+        pb.set_message(command.to_string());
+        pb.set_prefix(format!("{}   {} ->",
+            output_prefix(),
+            style(
+                format!("({}/{})", index, self.commands.len())
+            ).color256(8)
+        ));
+        pb
+    }
+
+    /// Runs a command, retrying it while it fails with a transient exit code
+    /// until the retry budget runs out. Any other failure is only retried if
+    /// the command (or its target) has a retry policy
+    fn run_command(
+        &self,
+        command: &Command,
+        cmd: &mut process::Command,
+        settings: &RunSettings,
+        pb: &ProgressBar) -> io::Result<process::Output> {
+        let stdin = command.stdin_content();
+        let policy = self.retry_policy(command);
+        let mut attempt = 0;
+        let mut retries = 0;
+
+        if !pb.is_hidden() {
+            pb.enable_steady_tick(Duration::from_millis(75));
+        }
+
+        loop {
+            // every attempt gets the full timeout, within the build's deadline
+            let deadline = match command.timeout {
+                Some(seconds) => {
+                    let timeout = Instant::now() + Duration::from_secs(seconds);
+                    Some(settings.deadline.map_or(timeout, |deadline| deadline
+                        .min(timeout)))
+                },
+                None => settings.deadline
+            };
+
+            let output = run_with_stdin(cmd, stdin.as_deref(),
+                command.merges_stderr(), deadline,
+                command.streams().then(|| LiveOutput::new(pb.clone(),
+                    command.line_filter(), settings.output_slots.clone())));
+
+            if let Ok(output) = &output {
+                // a captured exit status is data rather than a failure, but a
+                // successful command can still fail to write its outputs
+                let failed = (!output.status.success()
+                    && command.capture_status.is_none()
+                    && !command.requests_stop(output))
+                    || (output.status.success()
+                        && command.missing_output().is_some());
+
+                if attempt < settings.transient_retries
+                    && settings.is_transient(output.status)
+                    && !command.requests_stop(output) {
+                    attempt += 1;
+                    pb.set_message(format!("{} (retry {}/{})",
+                        command,
+                        attempt,
+                        settings.transient_retries
+                    ));
+                    continue;
+                }
+
+                if let Some(policy) = policy
+                    .filter(|policy| failed && retries < policy.count) {
+                    retries += 1;
+                    pb.set_message(format!("{} (retry {}/{})",
+                        command,
+                        retries,
+                        policy.count
+                    ));
+                    std::thread::sleep(policy.delay(retries));
+                    continue;
+                }
+            }
+
+            return output;
+        }
+    }
+
+    /// Works out how a command that has run went, reporting it and finishing
+    /// its spinner. Also returns how the build of the target ends if this
+    /// command ends it
+    fn finish_command(
+        &self,
+        command: &Command,
+        output: io::Result<process::Output>,
+        timing: CommandTiming,
+        pb: &ProgressBar,
+        ascii: bool,
+        captured: &mut HashMap<String, String>)
+        -> (CommandResult, Option<BuildOutcome>) {
+        let quiet = command.quiet == Some(true);
+        let started = timing.started;
+        let mut command_result = CommandResult {
+            command: command.to_string(),
+            status: CommandStatus::Succeeded,
+            exit_code: None,
+            duration_ms: started.elapsed().as_millis(),
+            reason: None
+        };
+
+        let output = match output {
+            Ok(output) => output,
+            Err(error) if error.kind() == io::ErrorKind::TimedOut
+                && command.timeout.is_some_and(|seconds|
+                    started.elapsed() >= Duration::from_secs(seconds)) => {
+                // the command's own timeout ran out, which is a failure of
+                // the command rather than of the whole build
+                print_error(format!("Command '{}' in target '{}' timed out \
+                    after {} second(s)", command, self.target,
+                    command.timeout.unwrap_or_default()).as_str(), false,
+                    "timeout");
+
+                let message = format!("{} {} {}",
+                    symbol(&RED_CROSS, ascii),
+                    style("Timed out").red(),
+                    command
+                );
+
+                pb.set_prefix(output_prefix());
+                pb.finish_with_message(message.clone());
+                if quiet {
+                    say!("{}", message);
+                }
+
+                command_result.fail(format!("timed out after {} second(s)",
+                    command.timeout.unwrap_or_default()));
+                return (command_result, Some(BuildOutcome::Failed));
+            },
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                pb.finish_and_clear();
+                print_error(format!("Command '{}' in target '{}' was killed \
+                    because it ran out of time", command, self.target)
+                    .as_str(), false, "timeout");
+                command_result.fail("ran out of time".to_string());
+                return (command_result, Some(BuildOutcome::TimedOut));
+            },
+            Err(_) => {
+                pb.finish_and_clear();
+                print_error(format!("Failed to execute command '{}'",
+                    command.command).as_str(),
+                    true,
+                    ""
+                );
+                command_result.fail("could not be started".to_string());
+                return (command_result, Some(BuildOutcome::Failed));
+            }
+        };
+
+        command_result.exit_code = output.status.code();
+
+        // the command may ask for the build to be stopped gracefully,
+        // in which case its exit status is not treated as a failure
+        if command.requests_stop(&output) {
+            let message = format!("{} {} {}",
+                symbol(&STOP_SIGN, ascii),
+                style("Stopped").yellow(),
+                command
+            );
+
+            pb.set_prefix(output_prefix());
+            pb.finish_with_message(message.clone());
+            if quiet {
+                say!("{}", message);
+            }
+
+            command_result.status = CommandStatus::Stopped;
+            return (command_result, Some(BuildOutcome::Stopped));
+        }
+
+        if let Some(name) = &command.capture_status {
+            // the exit status is captured rather than treated as a
+            // failure, so that later commands can act on it
+            captured.insert(name.clone(), output.status
+                .code()
+                .map(|code| code.to_string())
+                .unwrap_or_default());
+        } else if !output.status.success() {
+            let s = command.error_output(&output);
+
+            print_error_with_output(
+                format!("Failed to execute command '{}':",
+                command.command).as_str(),
+                &s,
+                false,
+                ""
+            );
+            command_result.fail(match output.status.code() {
+                Some(code) => format!("exited with code {}", code),
+                None => "was killed by a signal".to_string()
+            });
+        } else if let Some(path) = command.missing_output() {
+            print_error(
+                format!("Command '{}' finished without writing '{}'",
+                command.command, path).as_str(),
+                false,
+                "outputs"
+            );
+            command_result.fail(format!("finished without writing '{}'",
+                path));
+        } else if let Some(golden) = &command.golden {
+            if let Some(diff) =
+                command.golden_mismatch(golden, &output.stdout) {
+                print_error(
+                    format!("Output of '{}' does not match '{}':\n\n{}",
+                    command.command, golden, diff).as_str(),
+                    false,
+                    "golden"
+                );
+                command_result.fail(format!("output does not match '{}'",
+                    golden));
+            }
+        }
+
+        // set finish message
+        let failed = command_result.status == CommandStatus::Failed;
+        if let Some(name) = command.capture.as_ref().filter(|_| !failed) {
+            captured.insert(name.clone(),
+                String::from_utf8_lossy(&output.stdout).trim().to_string());
+        }
+
+        let finish_emoji = if failed { &RED_CROSS } else { &GREEN_TICK };
+
+        let mut message = format!("{} {} {}",
+            symbol(finish_emoji, ascii),
+            style("Finished").blue(),
+            command
+        );
+        if !failed {
+            message += format!(" {}", style(describe_duration(
+                command_result.duration_ms as u64, timing.previous_ms))
+                .color256(8)).as_str();
+        }
+
+        pb.set_prefix(output_prefix());
+        pb.finish_with_message(message.clone());
+
+        if quiet && failed {
+            say!("{}", message);
+        }
+
+        (command_result, failed.then_some(BuildOutcome::Failed))
+    }
+
+    fn build(&self, lock: &mut CoyoteLock) -> TargetResult {
+        let mut result = TargetResult {
+            target: self.target.clone(),
+            outcome: BuildOutcome::Finished,
+            duration_ms: 0,
+            commands: Vec::new()
+        };
+        let started = Instant::now();
+
+        // variables captured from commands as the target builds, which are
+        // substituted into later commands right before they run
+        let has_captures = !self.captured_variables().is_empty();
+        let mut captured: HashMap<String, String> = HashMap::new();
+
+        // a target timeout can only bring the build's deadline forward
+        let build_deadline = lock.deadline;
+        if let Some(seconds) = lock.target_timeouts.get(&self.target) {
+            let deadline = started + Duration::from_secs(*seconds);
+            lock.deadline = Some(build_deadline
+                .map_or(deadline, |build_deadline| build_deadline.min(deadline)));
+        }
+
+        if self.parallel == Some(true) {
+            if has_captures {
+                format_error(format!("Commands in target '{}' cannot use \
+                    'capture_status' or 'capture', as they run in parallel",
+                    self.target)
+                    .as_str(), true, "parallel");
+            }
+            self.build_parallel(lock, &mut result);
+        } else {
+            self.build_sequential(lock, &mut result, &mut captured);
+        }
+
+        lock.deadline = build_deadline;
+
+        if result.outcome == BuildOutcome::Failed {
+            self.run_failure_hooks(lock, has_captures.then_some(&captured));
+        }
+
+        result.duration_ms = started.elapsed().as_millis();
+        result
+    }
+
+    /// Reports which commands would run and which would be skipped, without
+    /// running any of them
+    fn dry_run(&self, lock: &mut CoyoteLock) {
+        let mut state = BuildState { previous_ran: false };
+        let captured = HashMap::new();
+
+        for (index, command) in self.commands.iter().enumerate() {
+            let command = &self.shown(command);
+            let id = format!("{}:{}", self.target, command);
+
+            // an earlier command may be what creates the input, so a missing
+            // one is reported rather than treated as an error
+            let missing = command.input_paths()
+                .into_iter()
+                .find(|path| fs::metadata(path).is_err());
+            if let Some(path) = missing {
+                say!("[{}:{}] {} {} ('{}' does not exist yet)", self.target,
+                    index, style("Would run").cyan(), command.command_line(),
+                    path);
+                state.previous_ran = true;
+                continue;
+            }
+
+            // commands that would be skipped count as not having run for the
+            // `after_ran` of the next command
+            state.previous_ran =
+                !self.skip_command(command, &id, lock, &state, &captured);
+
+            if state.previous_ran {
+                say!("[{}:{}] {} {}", self.target, index,
+                    style("Would run").cyan(), command.command_line());
+            } else {
+                say!("[{}:{}] {} {} (condition not met)", self.target, index,
+                    style("Would skip").yellow(), command.command_line());
+            }
+        }
+    }
+
+    fn build_sequential(
+        &self,
+        lock: &mut CoyoteLock,
+        result: &mut TargetResult,
+        captured: &mut HashMap<String, String>) {
+        let mut index = 1;
+        let mut state = BuildState { previous_ran: false };
+        let has_captures = !self.captured_variables().is_empty();
+
+        for command in &self.commands {
+            let patched;
+            let command = if has_captures {
+                let mut command = command.clone();
+                command.patch_references(&self.target, captured, None);
+                patched = command;
+                &patched
+            } else {
+                command
+            };
+
+            let id = format!("{}:{}", self.target, command);
+
+            if lock.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                result.outcome = BuildOutcome::TimedOut;
+                break;
+            }
+
+            if self.skip_command(command, &id, lock, &state, captured) {
+                // if the condition is not met, skip this compilation step
+                state.previous_ran = false;
+                result.commands.push(CommandResult::skipped(command));
+                continue;
+            }
+
+            let Some(mut cmd) = self.spawnable(command, lock) else {
+                lock.record(id, true);
+                result.commands.push(CommandResult::failed(command));
+                result.outcome = BuildOutcome::Failed;
+                break;
+            };
+
+            let pb = self.spinner(command, index);
+            let timing = CommandTiming {
+                started: Instant::now(),
+                previous_ms: lock.durations.get(&id).copied()
+            };
+            let output =
+                self.run_command(command, &mut cmd, &lock.run_settings(), &pb);
+
+            let (command_result, outcome) = self.finish_command(command,
+                output, timing, &pb, lock.ascii, captured);
+            lock.record_duration(&id, &command_result);
+            result.commands.push(command_result);
+
+            if let Some(outcome) = outcome {
+                if outcome == BuildOutcome::Failed {
+                    lock.record(id, true);
+                }
+                result.outcome = outcome;
+                break;
+            }
+
+            lock.record(id, false);
+            index += 1;
+            state.previous_ran = true;
+        }
+    }
+
+    /// Checks the conditions of every command up front, and then runs the
+    /// commands that are due at the same time (up to `--jobs` at once), each
+    /// with its own spinner
+    fn build_parallel(&self, lock: &mut CoyoteLock, result: &mut TargetResult) {
+        let mut state = BuildState { previous_ran: false };
+        let captured = HashMap::new();
+        let progress = MultiProgress::new();
+
+        // commands that will run, in order, with the slot their result goes
+        // into
+        let mut due: Vec<(usize, &Command, String, process::Command,
+            ProgressBar)> = Vec::new();
+
+        for command in &self.commands {
+            let id = format!("{}:{}", self.target, command);
+
+            if self.skip_command(command, &id, lock, &state, &captured) {
+                state.previous_ran = false;
+                result.commands.push(CommandResult::skipped(command));
+                continue;
+            }
+
+            let Some(cmd) = self.spawnable(command, lock) else {
+                lock.record(id, true);
+                result.commands.push(CommandResult::failed(command));
+                result.outcome = BuildOutcome::Failed;
+                return;
+            };
+
+            let pb = progress.add(self.spinner(command, due.len() + 1));
+            due.push((result.commands.len(), command, id, cmd, pb));
+            result.commands.push(CommandResult::skipped(command));
+            state.previous_ran = true;
+        }
+
+        let settings = lock.run_settings();
+        let ascii = lock.ascii;
+        let workers = lock.jobs.clamp(1, due.len().max(1));
+        let lock = Mutex::new(lock);
+        let queue = Mutex::new(due.into_iter());
+
+        // each worker keeps taking the next command until there are none
+        // left, so that no more than `--jobs` commands run at once
+        let finished: Vec<(usize, CommandResult, Option<BuildOutcome>)> =
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = (0..workers)
+                    .map(|_| scope.spawn(|| {
+                        let mut finished = Vec::new();
+                        loop {
+                            let next = queue.lock().unwrap().next();
+                            let Some((slot, command, id, mut cmd, pb)) = next
+                            else {
+                                break;
+                            };
+
+                            let timing = CommandTiming {
+                                started: Instant::now(),
+                                previous_ms: lock.lock().unwrap()
+                                    .durations.get(&id).copied()
+                            };
+                            let output = self.run_command(command, &mut cmd,
+                                &settings, &pb);
+                            let (command_result, outcome) = self
+                                .finish_command(command, output, timing, &pb,
+                                    ascii, &mut HashMap::new());
+
+                            let failed =
+                                command_result.status == CommandStatus::Failed;
+                            let mut lockfile = lock.lock().unwrap();
+                            lockfile.record_duration(&id, &command_result);
+                            lockfile.record(id, failed);
+                            finished.push((slot, command_result, outcome));
+                        }
+                        finished
+                    }))
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+
+        // every command has run to completion, so the build ends the worst
+        // way any of them ended it
+        let mut outcomes: Vec<BuildOutcome> = Vec::new();
+        for (slot, command_result, outcome) in finished {
+            result.commands[slot] = command_result;
+            outcomes.extend(outcome);
+        }
+
+        for outcome in [BuildOutcome::Stopped, BuildOutcome::Failed,
+            BuildOutcome::TimedOut] {
+            if outcomes.contains(&outcome) {
+                result.outcome = outcome;
+            }
+        }
+    }
+}
+
+/// How long a command took (eg. `(1.20s, 0.35s slower)`), compared to how
+/// long it took the last time it succeeded
+fn describe_duration(duration_ms: u64, previous_ms: Option<u64>) -> String {
+    let duration = Duration::from_millis(duration_ms);
+    let Some(previous_ms) = previous_ms else {
+        return format!("({:.2?})", duration);
+    };
+
+    let change = Duration::from_millis(duration_ms.abs_diff(previous_ms));
+    match duration_ms.cmp(&previous_ms) {
+        std::cmp::Ordering::Equal => format!("({:.2?}, same as last time)",
+            duration),
+        std::cmp::Ordering::Greater => format!("({:.2?}, {:.2?} slower)",
+            duration, change),
+        std::cmp::Ordering::Less => format!("({:.2?}, {:.2?} faster)",
+            duration, change)
+    }
+}
+
+/// Lowers (or raises) the scheduling priority of a command once it has been
+/// spawned, so that long builds don't hog the machine
+#[cfg(unix)]
+fn set_nice(cmd: &mut process::Command, nice: i32) {
+    use std::os::unix::process::CommandExt;
+
+    // SAFETY: setpriority is async-signal-safe, and nothing else is touched
+    // between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn set_nice(_cmd: &mut process::Command, _nice: i32) {
+    print_error("'nice' is only supported on Unix, so it is ignored", false,
+        "nice");
+}
+
+/// Runs a command to completion, piping `stdin` into it if given. With
+/// `merge_stderr`, stderr is written into the same pipe as stdout and the
+/// returned stderr is empty. If the deadline passes first, the command is
+/// killed and a `TimedOut` error is returned. When `stream` is given, each
+/// line of output is also printed through it as soon as it arrives
+fn run_with_stdin(
+    cmd: &mut process::Command,
+    stdin: Option<&[u8]>,
+    merge_stderr: bool,
+    deadline: Option<Instant>,
+    stream: Option<LiveOutput>) -> io::Result<process::Output> {
+    if stdin.is_none() && !merge_stderr && deadline.is_none()
+        && stream.is_none() {
+        return cmd.output();
+    }
+
+    let merged = if merge_stderr {
+        let (reader, writer) = io::pipe()?;
+        cmd.stdout(writer.try_clone()?).stderr(writer);
+        Some(reader)
+    } else {
+        cmd.stdout(process::Stdio::piped()).stderr(process::Stdio::piped());
+        None
+    };
+
+    let spawned = cmd
+        .stdin(if stdin.is_some() {
+            process::Stdio::piped()
+        } else {
+            process::Stdio::null()
+        })
+        .spawn();
+
+    // the command holds on to its copies of the merged pipe, which would keep
+    // the pipe from ever reaching end of file
+    if merged.is_some() {
+        cmd.stdout(process::Stdio::null()).stderr(process::Stdio::null());
+    }
+    let mut child = spawned?;
+
+    // write from another thread, so that a child filling its output pipes
+    // before reading all of stdin cannot deadlock
+    let writer = stdin.map(|stdin| {
+        let mut child_stdin = child.stdin.take().unwrap();
+        let content = stdin.to_vec();
+        std::thread::spawn(move || {
+            // the child may exit without reading everything, which is fine
+            let _ = child_stdin.write_all(&content);
+        })
+    });
+
+    // the output pipes are drained in the background while waiting, so that
+    // the child never blocks on a full pipe
+    fn drain(
+        pipe: Option<impl Read + Send + 'static>,
+        stream: Option<LiveOutput>,
+        to_stderr: bool) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut buffer = Vec::new();
+            let Some(pipe) = pipe else {
+                return buffer;
+            };
+
+            let Some(stream) = stream else {
+                let mut pipe = pipe;
+                let _ = pipe.read_to_end(&mut buffer);
+                return buffer;
+            };
+
+            let mut pipe = io::BufReader::new(pipe);
+            let mut line = Vec::new();
+            while let Ok(1..) = pipe.read_until(b'\n', &mut line) {
+                let text = String::from_utf8_lossy(&line);
+                stream.print(text.trim_end_matches(['\r', '\n']), to_stderr);
+
+                buffer.append(&mut line);
+            }
+            buffer
+        })
+    }
+
+    let stdout = match merged {
+        Some(reader) => drain(Some(reader), stream.clone(), false),
+        None => drain(child.stdout.take(), stream.clone(), false)
+    };
+    let stderr = drain(child.stderr.take(), stream.clone(), true);
+
+    let status = loop {
+        let Some(deadline) = deadline else {
+            break child.wait()?;
+        };
+
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            if let Some(stream) = &stream {
+                stream.finish();
+            }
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                "command ran past its deadline"));
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+
+    let output = process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default()
+    };
+    if let Some(stream) = &stream {
+        stream.finish();
+    }
+    Ok(output)
+}
+
+/// The lines of a command's streamed output that are shown
+#[derive(Clone)]
+struct LineFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>
+}
+
+impl LineFilter {
+    fn shows(&self, line: &str) -> bool {
+        self.include.as_ref().is_none_or(|include| include.is_match(line))
+            && !self.exclude.as_ref().is_some_and(|exclude| exclude
+                .is_match(line))
+    }
+}
+
+/// Prints the streamed output of a command. With `--max-inflight-output`,
+/// the output is only printed live while the command holds one of the
+/// slots, and is held back until it gets one (or finishes)
+#[derive(Clone)]
+struct LiveOutput {
+    pb: ProgressBar,
+    filter: LineFilter,
+    slots: Option<Arc<Mutex<usize>>>,
+    state: Arc<Mutex<LiveState>>
+}
+
+#[derive(Default)]
+struct LiveState {
+    // whether the command holds a slot (or no longer needs one)
+    live: bool,
+    finished: bool,
+
+    // lines held back until the command gets a slot, and whether each
+    // goes to stderr
+    pending: Vec<(String, bool)>
+}
+
+impl LiveOutput {
+    fn new(pb: ProgressBar, filter: LineFilter,
+        slots: Option<Arc<Mutex<usize>>>) -> Self {
+        LiveOutput { pb, filter, slots, state: Default::default() }
+    }
+
+    fn print(&self, line: &str, to_stderr: bool) {
+        if !self.filter.shows(line) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.pending.push((prefixed(line), to_stderr));
+        if !state.live && !state.finished {
+            state.live = self.take_slot();
+        }
+
+        if state.live || state.finished {
+            self.flush(&mut state.pending);
+        }
+    }
+
+    /// Prints whatever was held back and gives up the command's slot
+    fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.flush(&mut state.pending);
+        state.finished = true;
+        if std::mem::take(&mut state.live) {
+            if let Some(slots) = &self.slots {
+                *slots.lock().unwrap() += 1;
+            }
+        }
+    }
+
+    fn take_slot(&self) -> bool {
+        let Some(slots) = &self.slots else {
+            return true;
+        };
+
+        let mut free = slots.lock().unwrap();
+        if *free == 0 {
+            return false;
+        }
+        *free -= 1;
+        true
+    }
+
+    fn flush(&self, pending: &mut Vec<(String, bool)>) {
+        self.pb.suspend(|| for (line, to_stderr) in pending.drain(..) {
+            if to_stderr {
+                eprintln!("{}", line);
+            } else {
+                println!("{}", line);
+            }
+        });
+    }
+}
+
+/// Quotes a string as a DOT identifier
+fn dot_quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The exit code of a failed build, which is the number of commands that
+/// failed (capped to what fits in an exit code)
+fn failure_exit_code(results: &[TargetResult]) -> i32 {
+    CommandCounts::new(results).failed.clamp(1, 255) as i32
+}
+
+/// Lists the commands that failed during a `--keep-going` build, and the
+/// targets that were skipped because of them
+fn print_failures(results: &[TargetResult], skipped: &[&str]) {
+    let failed: Vec<(&str, &CommandResult)> = results
+        .iter()
+        .flat_map(|result| result.commands
+            .iter()
+            .filter(|command| command.status == CommandStatus::Failed)
+            .map(|command| (result.target.as_str(), command)))
+        .collect();
+
+    say_always!("[coyote] {}", style(format!("{} command(s) failed",
+        failed.len())).red());
+    for (target, command) in failed {
+        let reason = command.reason.as_deref().unwrap_or("failed");
+        say_always!("    {} {} {}", style(format!("[{}]", target)).red(),
+            command.command, style(format!("({})", reason)).color256(8));
+    }
+
+    for target in skipped {
+        say_always!("    {} '{}' (depends on a failed target)",
+            style("Skipped target").yellow(), target);
+    }
+}
+
+/// Prints how many commands actually ran versus how many were skipped, to show
+/// how effective incremental builds are. The time saved is estimated from how
+/// long each skipped command took the last time it ran
+fn print_stats(results: &[TargetResult], durations: &HashMap<String, u64>) {
+    let counts = CommandCounts::new(results);
+    let saved_ms: u64 = results
+        .iter()
+        .flat_map(|result| result.commands
+            .iter()
+            .filter(|command| command.status == CommandStatus::Skipped)
+            .filter_map(|command| durations
+                .get(&format!("{}:{}", result.target, command.command))))
+        .sum();
+
+    say_always!("[coyote] {}", style("Build statistics").cyan());
+    say_always!("    {} command(s) ran, taking {:.2?}",
+        counts.ran,
+        Duration::from_millis(counts.run_time_ms as u64)
+    );
+    say_always!("    {} command(s) skipped by run_if, saving about {:.2?}",
+        counts.skipped, Duration::from_millis(saved_ms));
+    say_always!("    {} command(s) failed", counts.failed);
+}
+
+/// Writes the reports that were asked for on the command line once the build
+/// ends, however it ends
+fn write_reports(
+    arguments: &Cli,
+    project_name: &str,
+    outcome: BuildOutcome,
+    started: Instant,
+    results: &[TargetResult]) {
+    if let Some(path) = &arguments.summary_json {
+        write_summary(path, project_name, outcome, started, results);
+    }
+
+    if let Some(path) = &arguments.print_durations_csv {
+        write_durations_csv(path, results);
+    }
+
+    if json_output() {
+        let summary = BuildSummary::new(project_name, outcome, started,
+            results);
+        println!("{}", serde_json::to_string_pretty(&summary)
+            .unwrap_or_default());
+    }
+}
+
+/// Quotes a CSV field if it contains anything that would break the row
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes the duration and status of every command to `--print-durations-csv`
+fn write_durations_csv(path: &str, results: &[TargetResult]) {
+    let mut csv = String::from("target,command,duration_ms,status\n");
+
+    for result in results {
+        for command in &result.commands {
+            csv += format!("{},{},{},{}\n",
+                csv_field(&result.target),
+                csv_field(&command.command),
+                command.duration_ms,
+                command.status
+            ).as_str();
+        }
+    }
+
+    if let Err(error) = fs::write(path, csv) {
+        format_error(format!("Failed to write durations to '{}': {}", path,
+            error).as_str(), true, "durations-csv");
+    }
+}
+
+/// Writes the report of the whole build (so far) to `--summary-json`
+fn write_summary(
+    path: &str,
+    project_name: &str,
+    outcome: BuildOutcome,
+    started: Instant,
+    results: &[TargetResult]) {
+    let summary = BuildSummary::new(project_name, outcome, started, results);
+
+    let written = serde_json::to_string_pretty(&summary)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+
+    if let Err(error) = written {
+        format_error(format!("Failed to write summary to '{}': {}", path,
+            error).as_str(), true, "summary-json");
+    }
+}
+
+/// Writes the result of a target into `<dir>/<target>.json`, replacing any
+/// characters in the target name that are unsafe in a filename
+fn write_target_result(dir: &str, result: &TargetResult) {
+    let filename: String = result.target
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+            c
+        } else {
+            '_'
+        })
+        .collect();
+
+    let path = Path::new(dir).join(filename + ".json");
+    let written = serde_json::to_string_pretty(result)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(&path, json).map_err(|e| e.to_string()));
+
+    if let Err(error) = written {
+        format_error(format!("Failed to write result to '{}': {}",
+            path.display(), error).as_str(), true, "result-dir");
+    }
+}
+
+fn write_lock(lock: &CoyoteLock) {
+    if let Ok(lock_json) = serde_json::to_string(lock) {
+        fs::write(&lock.path, lock_json).expect("Uh oh");
+    }
+    else {
+        format_error(format!("Failed to convert '{}' into JSON format.",
+            lock.path).as_str(),
+            true,
+            ""
+        );
+    }
+}
+
+/// Finds the first version number (eg. `11.4.0`) in the output of
+/// `<tool> --version`
+fn tool_version(tool: &Path) -> Option<Vec<u64>> {
+    let output = process::Command::new(tool).arg("--version").output().ok()?;
+    let text = String::from_utf8_lossy(&output.stdout).into_owned()
+        + &String::from_utf8_lossy(&output.stderr);
+
+    text.split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))
+        .map(parse_version)
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map_while(|part| part.parse::<u64>().ok())
+        .collect()
+}
+
+/// Compares two versions part by part, treating missing parts as 0
+fn compare_versions(a: &[u64], b: &[u64]) -> std::cmp::Ordering {
+    (0..a.len().max(b.len()))
+        .map(|i| a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
+/// Checks that every tool in `requires` is on PATH and satisfies its version
+/// constraint, failing with a list of everything that does not
+fn check_requirements(requires: &[String]) {
+    let mut unmet: Vec<String> = Vec::new();
+
+    for requirement in requires {
+        let (tool, constraint) = match requirement.find(['<', '>', '=']) {
+            Some(index) => requirement.split_at(index),
+            None => (requirement.as_str(), "")
+        };
+        let tool = tool.trim();
+
+        let Ok(path) = which::which(tool) else {
+            unmet.push(format!("{} (not found)", tool));
+            continue;
+        };
+
+        if constraint.is_empty() {
+            continue;
+        }
+
+        let operator_length = constraint
+            .find(|c: char| !"<>=".contains(c))
+            .unwrap_or(constraint.len());
+        let (operator, wanted) = constraint.split_at(operator_length);
+        let wanted = parse_version(wanted.trim());
+
+        let Some(found) = tool_version(&path) else {
+            unmet.push(format!("{} (could not determine its version)",
+                requirement));
+            continue;
+        };
+
+        let ordering = compare_versions(&found, &wanted);
+        let satisfied = match operator {
+            ">=" => ordering.is_ge(),
+            ">" => ordering.is_gt(),
+            "<=" => ordering.is_le(),
+            "<" => ordering.is_lt(),
+            "=" | "==" => ordering.is_eq(),
+            _ => {
+                format_error(format!("Unknown version constraint '{}' in \
+                    requirement '{}'", operator, requirement).as_str(), true,
+                    "requires");
+                false
+            }
+        };
+
+        if !satisfied {
+            let found: Vec<String> =
+                found.iter().map(|part| part.to_string()).collect();
+            unmet.push(format!("{} (found {})", requirement, found.join(".")));
+        }
+    }
+
+    if !unmet.is_empty() {
+        format_error(format!("The following required tools are missing or \
+            too old: {}", unmet.join(", ")).as_str(), true, "requires");
+    }
+}
+
+/// The build file for a recipe (or the default one), in the first format that
+/// exists. If there is none, this is the JSON path
+fn config_path(recipe: Option<&str>) -> String {
+    let stem = match recipe {
+        Some(recipe) => format!("{}/coyote-{}", recipe_dir(), recipe),
+        None => "./coyote".to_string()
+    };
+
+    CONFIG_EXTENSIONS
+        .iter()
+        .map(|extension| format!("{}.{}", stem, extension))
+        .find(|path| Path::new(path).is_file())
+        .unwrap_or(stem + ".json")
+}
+
+/// Where the lock file lives: `--lockfile` if given, otherwise
+/// `coyote-<recipe>.LOCK` for a recipe (so that recipes don't share
+/// timestamps) and `coyote.LOCK` for the default build file
+fn lock_path(recipe: Option<&str>, lockfile: Option<&str>) -> String {
+    match (lockfile, recipe) {
+        (Some(lockfile), _) => lockfile.to_string(),
+        (None, Some(recipe)) => format!("./coyote-{}.LOCK", recipe),
+        (None, None) => "./coyote.LOCK".to_string()
+    }
+}
+
+/// One job per CPU, for `--jobs auto`
+fn available_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |jobs| jobs.get())
+}
+
+/// Parses `--jobs`, which is a number of commands or `auto` (as is 0)
+fn parse_jobs(value: &str) -> Result<usize, String> {
+    match value {
+        "auto" | "0" => Ok(available_jobs()),
+        _ => value.parse::<usize>().map_err(|_| "expected a positive number \
+            or 'auto'".to_string())
+    }
+}
+
+/// Parses a `--target-timeout` of the form `<target>=<seconds>`
+fn parse_target_timeout(value: &str) -> Result<(String, u64), String> {
+    let Some((target, seconds)) = value.split_once('=') else {
+        return Err("expected <target>=<seconds>".to_string());
+    };
+
+    match seconds.parse::<u64>() {
+        Ok(seconds) => Ok((target.to_string(), seconds)),
+        Err(error) => Err(format!("invalid number of seconds: {}", error))
+    }
+}
+
+/// How long the file system has to be quiet before a change starts a build,
+/// so that a burst of writes only triggers one
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Set in the environment of the builds started by `--watch`, which run with
+/// the same arguments but must not start watching themselves
+const WATCH_CHILD_ENV: &str = "COYOTE_WATCH_CHILD";
+
+/// Builds the project, then builds it again every time one of its inputs (or
+/// the build file itself) changes. Each build is a separate run of coyote
+/// without `--watch`, so a failed build doesn't end the watch, and
+/// coyote.LOCK decides which commands the change affects
+fn watch(build_info: &CoyoteJson, config: &str) -> ! {
+    use notify::{ EventKind, RecursiveMode, Watcher };
+
+    let mut inputs: BTreeSet<&str> = build_info.executables
+        .iter()
+        .flat_map(|exec| &exec.commands)
+        .flat_map(|command| command.input_paths())
+        .collect();
+    inputs.insert(config);
+    inputs.extend(build_info.included.iter().map(String::as_str));
+
+    let globs: BTreeSet<&str> = build_info.executables
+        .iter()
+        .flat_map(|exec| &exec.commands)
+        .flat_map(|command| command.glob_patterns())
+        .collect();
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)
+        .unwrap_or_else(|error| {
+            format_error(format!("Failed to watch for changes: {}",
+                error).as_str(), true, "watch");
+            process::exit(-1);
+        });
+
+    // editors often replace a file rather than writing to it, so files are
+    // watched through the directory they are in
+    let mut watched: Vec<PathBuf> = Vec::new();
+    for input in &inputs {
+        let path = Path::new(input);
+        let (directory, mode, target) = if path.is_dir() {
+            (path, RecursiveMode::Recursive, None)
+        } else {
+            let parent = path.parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            (parent, RecursiveMode::NonRecursive, path.file_name())
+        };
+
+        let result = directory.canonicalize()
+            .map_err(notify::Error::io)
+            .and_then(|directory| {
+                watcher.watch(&directory, mode)?;
+                Ok(match target {
+                    Some(name) => directory.join(name),
+                    None => directory
+                })
+            });
+
+        match result {
+            Ok(path) => watched.push(path),
+            Err(error) => print_error(format!("Can't watch '{}': {}", input,
+                error).as_str(), false, "watch")
+        }
+    }
+
+    // a pattern's matches can appear anywhere below the directory it starts
+    // in, but only the paths that match it count as changes
+    let mut patterns: Vec<glob::Pattern> = Vec::new();
+    for pattern in &globs {
+        let (base, rest) = glob_base(pattern);
+        let result = Path::new(base).canonicalize()
+            .map_err(notify::Error::io)
+            .and_then(|directory| {
+                watcher.watch(&directory, RecursiveMode::Recursive)?;
+                glob::Pattern::new(&format!("{}/{}", glob::Pattern::escape(
+                    &directory.to_string_lossy()), rest))
+                    .map_err(|error| notify::Error::generic(error.msg))
+            });
+
+        match result {
+            Ok(pattern) => patterns.push(pattern),
+            Err(error) => print_error(format!("Can't watch '{}': {}", pattern,
+                error).as_str(), false, "watch")
+        }
+    }
+
+    let arguments: Vec<std::ffi::OsString> = std::env::args_os()
+        .skip(1)
+        .collect();
+
+    loop {
+        let executable = std::env::current_exe()
+            .unwrap_or_else(|_| PathBuf::from("coyote-build"));
+        if let Err(error) = process::Command::new(executable)
+            .args(&arguments)
+            .env(WATCH_CHILD_ENV, "1")
+            .status() {
+            format_error(format!("Failed to start a build: {}", error)
+                .as_str(), true, "watch");
+        }
+
+        say!("{}", style(format!("[coyote] Watching {} file(s) for changes",
+            watched.len() + patterns.len())).color256(8));
+
+        // wait for a change to one of the inputs, then for the changes to
+        // settle down
+        let relevant = |event: notify::Result<notify::Event>| match event {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => event
+                .paths
+                .into_iter()
+                .find(|path| watched.iter().any(|input| path.starts_with(input))
+                    || patterns.iter().any(|pattern| pattern.matches_path(path))),
+            _ => None
+        };
+
+        let changed = loop {
+            match receiver.recv() {
+                Ok(event) => if let Some(path) = relevant(event) {
+                    break path;
+                },
+                Err(_) => process::exit(-1)
+            }
+        };
+        while receiver.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+
+        say!("{}", style(format!("[coyote] '{}' changed, building again",
+            changed.display())).cyan());
+    }
+}
+
+/// The names of the recipes (`coyote-<recipe>.json`, or any other build file
+/// format) in the recipe directory
+fn find_recipes() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(recipe_dir()) else {
+        return Vec::new();
+    };
+
+    let mut recipes: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let (stem, extension) = name.rsplit_once('.')?;
+            stem.strip_prefix("coyote-")
+                .filter(|_| CONFIG_EXTENSIONS.contains(&extension))
+                .map(|recipe| recipe.to_string())
+        })
+        .collect();
+
+    recipes.sort();
+    recipes.dedup();
+    recipes
+}
+
+fn read_config(recipe: Option<&str>) -> String {
+    match fs::read_to_string(config_path(recipe)) {
+        Ok(x) => x,
+        Err(_) => {
+            match recipe {
+                Some(recipe) => format_error(format!(
+                    "Couldn't find file for recipe '{}' (note - recipe JSON fi\
+                    les must be prefixed with 'coyote-' to be recognised)",
+                    recipe).as_str(), true, "recipe"
+                ),
+                None => format_error(
+                    "Directory does not contain `coyote.json` (or \
+                    `coyote.yaml` or `coyote.toml`)",
+                    true,
+                    ""
+                )
+            }
+            process::exit(-1);
+        }
+    }
+}
+
+/// Reads the build file given to `--config`, which is either a local path or
+/// (with `--allow-remote`) a URL, checking its SHA-256 if one is given
+fn load_config(source: &str, allow_remote: bool, sha256: Option<&str>)
+    -> String {
+    let remote = source.starts_with("http://")
+        || source.starts_with("https://");
+
+    if remote && !allow_remote {
+        format_error(format!("Refusing to fetch '{}' without --allow-remote",
+            source).as_str(), true, "config");
+    }
+
+    let contents = if remote {
+        reqwest::blocking::get(source)
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.bytes())
+            .map(|bytes| bytes.to_vec())
+            .map_err(|error| error.to_string())
+    } else {
+        fs::read(source).map_err(|error| error.to_string())
+    };
+
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err(error) => {
+            format_error(format!("Failed to read '{}': {}", source, error)
+                .as_str(), true, "config");
+            process::exit(-1);
+        }
+    };
+
+    if let Some(expected) = sha256 {
+        let actual: String = Sha256::digest(&contents)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            format_error(format!("Checksum of '{}' does not match (expected \
+                {}, got {})", source, expected, actual).as_str(), true,
+                "config");
+        }
+    }
+
+    match String::from_utf8(contents) {
+        Ok(contents) => contents,
+        Err(_) => {
+            format_error(format!("'{}' is not valid UTF-8", source).as_str(),
+                true, "config");
+            process::exit(-1);
+        }
+    }
+}
+
+/// The formats build files can be written in
+enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml
+}
+
+impl ConfigFormat {
+    /// Goes by the extension of the file, which is JSON if it isn't known
+    fn of(path: &str) -> ConfigFormat {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json
+        }
+    }
+}
+
+fn parse_config(contents: &str, path: &str) -> CoyoteJson {
+    let parsed = match ConfigFormat::of(path) {
+        ConfigFormat::Json => serde_json::from_str(contents)
+            .map_err(|error| error.to_string()),
+        ConfigFormat::Yaml => serde_yaml::from_str(contents)
+            .map_err(|error| error.to_string()),
+        ConfigFormat::Toml => toml::from_str(contents)
+            .map_err(|error| error.to_string())
+    };
+
+    match parsed {
+        Ok(x) => x,
+        Err(error) => {
+            let name = Path::new(path)
+                .file_name()
+                .map_or(path.into(), |name| name.to_string_lossy());
+            format_error(format!("Malformed '{}' detected: {}", name, error)
+                .as_str(), true, "");
+            process::exit(-1);
+        }
+    }
+}
+
+/// Serializes a build file back into the format it was written in
+fn serialize_config(config: &CoyoteJson, path: &str) -> Result<String, String> {
+    match ConfigFormat::of(path) {
+        ConfigFormat::Json => serde_json::to_string_pretty(config)
+            .map(|json| json + "\n")
+            .map_err(|error| error.to_string()),
+        ConfigFormat::Yaml => serde_yaml::to_string(config)
+            .map_err(|error| error.to_string()),
+        ConfigFormat::Toml => toml::to_string_pretty(config)
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// Whether a YAML or TOML file has a `#` comment. Quoted strings are skipped,
+/// but anything more unusual (like a `#` in a multi-line string) counts too
+fn has_comments(contents: &str) -> bool {
+    contents.lines().any(|line| {
+        let mut quote = None;
+        let mut previous = ' ';
+        for c in line.chars() {
+            match (quote, c) {
+                (None, '#') if previous.is_whitespace() => return true,
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(open), _) if c == open && previous != '\\' =>
+                    quote = None,
+                _ => ()
+            }
+            previous = c;
+        }
+        false
+    })
+}
+
+/// Collects the keys of `raw` (as paths like `executables[0].run-if`) that
+/// are missing from `formatted`, which are the ones coyote doesn't know about
+fn dropped_keys(
+    raw: &serde_json::Value,
+    formatted: &serde_json::Value,
+    path: &str,
+    dropped: &mut Vec<String>) {
+    use serde_json::Value;
+
+    match (raw, formatted) {
+        (Value::Object(raw), Value::Object(formatted)) => {
+            for (key, value) in raw {
+                let key_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+
+                match formatted.get(key) {
+                    Some(formatted) => dropped_keys(value, formatted, &key_path,
+                        dropped),
+                    // unset optional keys are left out either way
+                    None if value.is_null() => (),
+                    None => dropped.push(key_path)
+                }
+            }
+        },
+        (Value::Array(raw), Value::Array(formatted)) => {
+            for (index, (raw, formatted)) in raw
+                .iter()
+                .zip(formatted)
+                .enumerate() {
+                dropped_keys(raw, formatted, &format!("{}[{}]", path, index),
+                    dropped);
+            }
+        },
+        _ => ()
+    }
+}
+
+/// Rewrites a config with consistent indentation and key order. The config is
+/// only parsed, never preprocessed, so its meaning is left unchanged
+fn format_config(recipe: Option<&str>, check: bool, force: bool) {
+    let path = config_path(recipe);
+    let contents = read_config(recipe);
+    let config = parse_config(&contents, &path);
+
+    // formatting goes through coyote's own idea of a build file, so anything
+    // it doesn't recognise (like a misspelled key) would silently disappear
+    let raw: Result<serde_json::Value, String> = match ConfigFormat::of(&path) {
+        ConfigFormat::Json => serde_json::from_str(&contents)
+            .map_err(|error| error.to_string()),
+        ConfigFormat::Yaml => serde_yaml::from_str(&contents)
+            .map_err(|error| error.to_string()),
+        ConfigFormat::Toml => toml::from_str(&contents)
+            .map_err(|error| error.to_string())
+    };
+    let mut dropped = Vec::new();
+    if let (Ok(raw), Ok(formatted)) = (raw, serde_json::to_value(&config)) {
+        dropped_keys(&raw, &formatted, "", &mut dropped);
+    }
+    if !dropped.is_empty() {
+        format_error(format!("'{}' has key(s) that coyote doesn't know, which \
+            formatting would remove: {}", path, dropped.join(", ")).as_str(),
+            true, "fmt");
+    }
+
+    let formatted = match serialize_config(&config, &path) {
+        Ok(formatted) => formatted,
+        Err(error) => {
+            format_error(format!("Failed to format '{}': {}", path, error)
+                .as_str(), true, "fmt");
+            process::exit(-1);
+        }
+    };
+
+    if formatted == contents {
+        return;
+    }
+
+    if check {
+        format_error(format!("'{}' is not formatted (run `coyote fmt` to fix \
+            it)", path).as_str(), false, "fmt");
+    }
+
+    // comments can't survive the round trip through serde
+    if !force && !matches!(ConfigFormat::of(&path), ConfigFormat::Json)
+        && has_comments(&contents) {
+        format_error(format!("'{}' has comments, which formatting would \
+            remove (pass --force to format it anyway)", path).as_str(), true,
+            "fmt");
+    }
+
+    if let Err(error) = fs::write(&path, formatted) {
+        format_error(format!("Failed to write '{}': {}", path, error).as_str(),
+            true, "fmt");
+    }
+
+    println!("{}", style(format!("[coyote] Formatted '{}'", path)).green());
+}
+
+/// Deletes the lock file and, if asked to, the outputs declared by the
+/// commands of the build file
+fn clean(recipe: Option<&str>, lockfile: Option<&str>, outputs: bool,
+    dirs: bool) {
+    let mut paths: Vec<String> = vec![lock_path(recipe, lockfile)];
+
+    if outputs {
+        let path = config_path(recipe);
+        let mut build_info = parse_config(&read_config(recipe), &path);
+        build_info.resolve_includes(&path);
+        build_info.preprocess();
+
+        for command in build_info.executables
+            .iter()
+            .flat_map(|exec| exec.all_commands()) {
+            for output in command.outputs.iter().flatten() {
+                if !paths.contains(output) {
+                    paths.push(output.clone());
+                }
+            }
+        }
+
+        // nothing is deleted if any output could take the project (or
+        // something outside of it) with it
+        for output in &paths[1..] {
+            if let Err(reason) = removable_output(output) {
+                format_error(format!("Refusing to delete output '{}': {}",
+                    output, reason).as_str(), true, "clean");
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for path in &paths {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            continue;
+        };
+
+        let result = if metadata.is_dir() {
+            if !dirs {
+                print_error(format!("Skipping directory '{}' (pass --dirs \
+                    to delete it)", path).as_str(), false, "clean");
+                continue;
+            }
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+
+        match result {
+            Ok(()) => {
+                say!("[coyote] {} {}", style("Removed").yellow(), path);
+                removed += 1;
+            },
+            Err(error) => format_error(format!("Failed to remove '{}': {}",
+                path, error).as_str(), true, "clean")
+        }
+    }
+
+    if removed == 0 {
+        say!("{}", style("[coyote] Nothing to clean").green());
+    }
+}
+
+/// Checks that an output is safe for `clean` to delete: a path inside the
+/// project directory, which isn't the project directory itself
+fn removable_output(path: &str) -> Result<(), String> {
+    let output = Path::new(path);
+    if path.trim().is_empty() {
+        return Err("the path is empty".to_string());
+    }
+
+    if output.has_root() {
+        return Err("the path is absolute".to_string());
+    }
+
+    let mut depth = 0;
+    for component in output.components() {
+        match component {
+            std::path::Component::Normal(_) => depth += 1,
+            std::path::Component::CurDir => (),
+            _ => return Err("the path leaves the project directory"
+                .to_string())
+        }
+    }
+
+    if depth == 0 {
+        return Err("the path is the project directory".to_string());
+    }
+
+    // symlinked directories along the way could still lead somewhere else
+    // (an output that is itself a symlink only has the link deleted)
+    let parent = match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    };
+    if let (Ok(root), Ok(parent)) = (std::env::current_dir()
+        .and_then(|dir| dir.canonicalize()), parent.canonicalize()) {
+        if !parent.starts_with(&root) {
+            return Err("the path leads outside of the project directory"
+                .to_string());
+        }
+    }
+
+    Ok(())
+}
 
-        for (k, v) in self.variables.as_object().unwrap() {
-            let key = k.as_str().to_string();
-            let value = v.as_str().unwrap().to_string();
+fn self_update(check_only: bool) {
+    let current = self_update::cargo_crate_version!();
 
-            let patched = patch_string(&value, &variables);
-            variables.insert(key.clone(), check_var_string(patched, key));
+    // the release must publish a SHA256SUMS asset, which the download is
+    // checked against before the running binary is swapped out
+    let updater = self_update::backends::github::Update::configure()
+        .repo_owner("Pougher")
+        .repo_name("coyote")
+        .bin_name("coyote-build")
+        .current_version(current)
+        .checksum_from_asset("SHA256SUMS")
+        .show_download_progress(true)
+        .no_confirm(true)
+        .build();
+
+    let updater = match updater {
+        Ok(updater) => updater,
+        Err(error) => {
+            format_error(format!("Failed to configure the updater: {}", error)
+                .as_str(), true, "self-update");
+            process::exit(-1);
         }
+    };
 
-        // go through all commands and fill in all strings with preprocessing
-        // data
-        for exec in &mut self.executables {
-            for command in &mut exec.commands {
-                let processed = check_var_string(patch_variable_references(
-                    &command.command,
-                    &variables
-                ), command.command.clone());
-                command.command = processed;
+    if check_only {
+        // only releases whose semver is strictly greater than this build are
+        // returned, so an empty list means we are up to date
+        let newer = match updater.get_newer_releases() {
+            Ok(releases) => releases,
+            Err(error) => {
+                format_error(format!("Failed to fetch releases: {}", error)
+                    .as_str(), true, "self-update");
+                process::exit(-1);
+            }
+        };
 
-                let mut modified_arguments: Vec<String> = Vec::new();
+        match newer.latest() {
+            Some(release) => println!("{}", style(format!("[coyote] Version {} \
+                is available (currently {})", release.version(), current))
+                .yellow()),
+            None => println!("{}", style(format!("[coyote] Already up to date \
+                ({})", current)).green())
+        }
+        return;
+    }
 
-                // loop through arguments and patch them
-                for argument in &mut command.arguments {
-                    let processed = check_var_string(patch_variable_references(
-                        &argument,
-                        &variables
-                    ), argument.clone());
+    match updater.update() {
+        Ok(status) if status.is_updated() => println!("{}",
+            style(format!("[coyote] Updated to {}", status.version()))
+            .green()),
+        Ok(_) => println!("{}", style(format!("[coyote] Already up to date \
+            ({})", current)).green()),
+        Err(error) => format_error(format!("Failed to update: {}", error)
+            .as_str(), true, "self-update")
+    }
+}
 
-                    modified_arguments.push(processed);
-                }
+fn main() {
+    let arguments = Cli::parse();
+    let _ = OUTPUT_PREFIX.set(arguments.prefix.clone());
+    let _ = JSON_OUTPUT.set(arguments.format.as_deref() == Some("json"));
+    let _ = VERBOSE.set(arguments.verbose);
+    let _ = NO_BACKTICKS.set(arguments.no_backticks);
 
-                command.arguments = modified_arguments;
+    if let Some(dir) = &arguments.recipe_dir {
+        if !Path::new(dir).is_dir() {
+            format_error(format!("Recipe directory '{}' does not exist", dir)
+                .as_str(), true, "recipe");
+        }
+        let _ = RECIPE_DIR.set(dir.trim_end_matches('/').to_string());
+    }
 
-                // finally, loop through all of the run_ifs and patch them
-                if let Some(ref runifs) = &command.run_if {
-                    let mut modified_runif: Vec<String> = Vec::new();
+    // set before anything is spawned, so that backtick substitutions also see
+    // it
+    if arguments.offline {
+        std::env::set_var("COYOTE_OFFLINE", "1");
+    }
 
-                    for argument in runifs.into_iter() {
-                        let processed = check_var_string(
-                            patch_variable_references(
-                                &argument,
-                                &variables
-                            ),
-                            argument.clone()
-                        );
+    if let Some(Subcommands::Fmt { recipe, check, force }) =
+        &arguments.subcommand {
+        format_config(recipe.as_deref(), *check, *force);
+        return;
+    }
 
-                        modified_runif.push(processed);
-                    }
+    if let Some(Subcommands::VerifyInputs { recipe }) = &arguments.subcommand {
+        let path = config_path(recipe.as_deref());
+        let mut build_info = parse_config(&read_config(recipe.as_deref()),
+            &path);
+        build_info.resolve_includes(&path);
+        build_info.resolve_extends();
+        build_info.preprocess();
 
-                    command.run_if = Some(modified_runif);
-                }
-            }
+        if !build_info.verify_inputs() {
+            process::exit(-1);
         }
+        return;
     }
-}
 
-impl Command {
-    fn to_string(&self) -> String {
-        format!("{} {}", self.command, self.arguments.join(" "))
+    if let Some(Subcommands::Clean { recipe, outputs, dirs }) =
+        &arguments.subcommand {
+        clean(recipe.as_deref(), arguments.lockfile.as_deref(), *outputs,
+            *dirs);
+        return;
     }
-}
 
-impl Executable {
-    fn build(&self, lock: &mut CoyoteLock) {
-        let mut index = 1;
+    if let Some(Subcommands::SelfUpdate { check_only }) = &arguments.subcommand {
+        self_update(*check_only);
+        return;
+    }
 
-        for command in &self.commands {
-            // firstly, check if the run_if condition is set and whether or not
-            // it is met
-            if let Some(condition) = &command.run_if {
-                if !lock.rebuild {
-                    if !condition_met(condition, self.target.clone(), lock) {
-                        // if the condition is not met, skip this compilation
-                        // step
-                        continue;
-                    }
-                }
-            }
+    // if there is a recipe present, use that JSON file instead of the default.
+    // NOTE: Each recipe keeps its own lock file (see `lock_path`)
+    let contents = match &arguments.config {
+        Some(source) => load_config(source, arguments.allow_remote,
+            arguments.config_sha256.as_deref()),
+        None => read_config(arguments.recipe.as_deref())
+    };
 
-            let mut cmd = process::Command::new(command.command.clone());
-            cmd.args(command.arguments.clone());
+    // convert the build file into a struct with serde
+    let path = match &arguments.config {
+        Some(source) => source.clone(),
+        None => config_path(arguments.recipe.as_deref())
+    };
+    let mut build_info = parse_config(&contents, &path);
+    build_info.resolve_includes(&path);
 
-            // setup spinner for current command
-            let spinner_style =
-                ProgressStyle::with_template(
-                    "{prefix:.bold.dim} {spinner} {wide_msg}"
-                )
-                .unwrap()
-                .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ");
+    // `--target` takes the place of the entry target
+    let entry = match &arguments.target {
+        Some(target) => {
+            build_info.find_executable(target, "target");
+            Some(target.clone())
+        },
+        None => build_info.entry_target()
+    };
+    build_info.resolve_extends();
 
-            let pb = ProgressBar::new_spinner();
+    if arguments.print_targets_json {
+        build_info.print_targets_json();
+        return;
+    }
 
-            pb.set_style(spinner_style);
-            pb.enable_steady_tick(Duration::from_millis(75));
-            pb.set_message(command.to_string());
-            pb.set_prefix(format!("   {} ->",
-                style(
-                    format!("({}/{})", index, self.commands.len())
-                ).color256(8)
-            ));
-
-            if let Ok(output) = cmd.output() {
-                let mut finish_emoji = GREEN_TICK;
-                if !output.status.success() {
-                    // convert stderr to string
-                    let s = match str::from_utf8(&output.stderr) {
-                        Ok(v) => v,
-                        Err(_) => process::exit(-1)
-                    }.to_owned();
-
-                    format_error(
-                        format!("Failed to execute command '{}': \n\n{}",
-                        command.command, s).as_str(),
-                        false,
-                        ""
-                    );
-                    finish_emoji = RED_CROSS;
-                }
+    if arguments.list {
+        build_info.list();
+        return;
+    }
 
-                // set finish message
-                pb.set_prefix("");
-                pb.finish_with_message(
-                    format!("{} {} {}",
-                        finish_emoji,
-                        style("Finished").blue(),
-                        command.to_string()
-                    )
-                );
-                pb.finish();
-            } else {
-                format_error(format!("Failed to execute command '{}'",
-                    command.command).as_str(),
-                    true,
-                    ""
-                );
+    let overrides: Vec<(String, String)> = arguments.vars
+        .iter()
+        .map(|var| match var.split_once('=') {
+            Some((name, value)) if !name.is_empty() =>
+                (name.to_string(), value.to_string()),
+            _ => {
+                format_error(format!("Invalid variable '{}' (expected \
+                    KEY=VALUE)", var).as_str(), true, "var");
+                process::exit(-1);
             }
+        })
+        .collect();
+    build_info.override_variables(&overrides);
 
-            index += 1;
-        }
+    // if only a single command has been requested, print it and exit before
+    // preprocessing anything else or touching coyote.LOCK
+    if let Some(path) = &arguments.print_command {
+        build_info.print_command(path, arguments.json);
+        return;
     }
-}
 
-fn main() {
-    let arguments = Cli::parse();
-    let mut contents = String::new();
+    // preprocess the build information
+    build_info.preprocess();
 
-    // if there is a recipe present, use that JSON file instead of the default.
-    // NOTE: All recipes operate on one coyote.LOCK file
-    if let Some(recipe) = arguments.recipe {
-        contents = match fs::read_to_string(
-            "./coyote-".to_string() + &recipe + ".json") {
-            Ok(x) => x,
-            Err(_) => {
-                format_error(format!(
-                    "Couldn't find file for recipe '{}' (note - recipe JSON fi\
-                    les must be prefixed with 'coyote-' to be recognised)",
-                    recipe).as_str(), true, "recipe"
-                );
-                process::exit(-1);
+    if arguments.warn_unused_vars || arguments.error_unused_vars {
+        let unused = build_info.unused_variables();
+        if !unused.is_empty() {
+            let message = format!("Variable(s) declared but never used: {}",
+                unused.join(", "));
+            if arguments.error_unused_vars {
+                format_error(message.as_str(), true, "unused-vars");
             }
-        };
+            print_error(message.as_str(), false, "unused-vars");
+        }
+    }
 
-        println!("{}", style(format!("[coyote] Building recipe '{}'", recipe))
-            .green());
-    } else {
-        contents = match fs::read_to_string("./coyote.json") {
-            Ok(x) => x,
-            Err(_) => {
-                format_error(
-                    "Directory does not contain `coyote.json`",
-                    true,
-                    ""
-                );
-                process::exit(-1);
-            }
-        };
+    if let Some(path) = &arguments.dump_resolved {
+        build_info.dump_resolved(path);
+        return;
     }
 
-    // convert the coyote.json file into a struct with serde
-    let build_result: Result<CoyoteJson, serde_json::Error> =
-        serde_json::from_str(&contents);
+    if let Some(scope) = &arguments.dump_env {
+        build_info.dump_env(scope);
+        return;
+    }
 
-    let mut build_info: CoyoteJson = match build_result {
-        Ok(x) => x,
-        Err(error) => {
-            format_error(format!("Malformed 'coyote.json' detected: {}",
-                error).as_str(), true, "");
-            process::exit(-1);
-        }
+    if arguments.print_plan_dot {
+        println!("{}", build_info.plan_dot());
+        return;
+    }
+
+    // `--interactive` takes the place of both `--target` and the entry
+    // target, once nothing is left that could return without building
+    let selected = if arguments.interactive && Term::stderr().is_term() {
+        Some(build_info.select_targets())
+    } else {
+        entry.map(|entry| vec![entry])
     };
 
-    // open coyote.LOCK if it exists, and if it does not exist then create a
+    let _ = QUIET.set(arguments.quiet || json_output());
+
+    if arguments.watch && std::env::var_os(WATCH_CHILD_ENV).is_none() {
+        watch(&build_info, &path);
+    }
+
+    if let Some(recipe) = &arguments.recipe {
+        say!("{}", style(format!("[coyote] Building recipe '{}'", recipe))
+            .green());
+    }
+
+    // open the lock file if it exists, and if it does not exist then create a
     // new one
-    let lock_contents = match fs::read_to_string("./coyote.LOCK") {
+    let lock_file = lock_path(arguments.recipe.as_deref(),
+        arguments.lockfile.as_deref());
+    let lock_contents = match fs::read_to_string(&lock_file) {
         Ok(x) => x,
         Err(_) => {
-            // file does not exist
-            if let Ok(_) = fs::File::create("./coyote.LOCK") {
+            // file does not exist (a dry run leaves it that way)
+            if arguments.dry_run || fs::File::create(&lock_file).is_ok() {
                 "".to_string()
             } else {
-                format_error("Failed to create 'coyote.LOCK", true, "");
+                format_error(format!("Failed to create '{}'", lock_file)
+                    .as_str(), true, "lockfile");
                 process::exit(-1);
             }
         }
@@ -543,49 +5581,701 @@ fn main() {
         Ok(x) => x,
         Err(x) => {
             if !lock_contents.is_empty() {
-                format_error(format!("Malformed 'coyote.LOCK' detected: {}",
-                    x).as_str(), true, "");
+                format_error(format!("Malformed '{}' detected: {}",
+                    lock_file, x).as_str(), true, "");
             }
             CoyoteLock::new()
         }
     };
 
+    lockfile.path = lock_file;
+
     lockfile.rebuild = arguments.rebuild;
+    lockfile.retry_failed = arguments.retry_failed;
+    let original_modified = lockfile.last_modified.clone();
+    lockfile.checkpoint_modified = original_modified.clone();
+    let original_hash = lockfile.last_hash.clone();
+    lockfile.checkpoint_hash = original_hash.clone();
 
-    // preprocess the build information
-    build_info.preprocess();
+    lockfile.resume = arguments.resume;
+    if !arguments.resume {
+        lockfile.completed.clear();
+    } else if lockfile.completed.is_empty() {
+        say!("{}", style("[coyote] No interrupted build to resume").yellow());
+    }
+
+    if !arguments.retry_failed {
+        lockfile.last_failed.clear();
+    } else if lockfile.last_failed.is_empty() {
+        say!("{}", style("[coyote] No failed commands to retry").yellow());
+    }
+    lockfile.offline = arguments.offline;
+    lockfile.resolve_programs = !arguments.no_resolve;
+    lockfile.nice = build_info.nice;
+    lockfile.container_runtime = build_info.container_runtime.clone();
+    lockfile.shell = build_info.shell.as_ref().map(|shell| {
+        match shlex::split(shell).filter(|words| !words.is_empty()) {
+            Some(words) => words,
+            None => {
+                format_error(format!("Invalid shell '{}'", shell).as_str(),
+                    true, "shell");
+                process::exit(-1);
+            }
+        }
+    });
+    lockfile.ascii = arguments.ascii;
+    lockfile.only_outputs_missing = arguments.only_outputs_missing;
+    lockfile.env = build_info.resolved_env.clone();
+
+    if let Some(dir) = &arguments.result_dir {
+        if let Err(error) = fs::create_dir_all(dir) {
+            format_error(format!("Failed to create result directory '{}': {}",
+                dir, error).as_str(), true, "result-dir");
+        }
+    }
+    lockfile.transient_exit_codes = build_info.transient_exit_codes
+        .clone()
+        .unwrap_or_default();
+    lockfile.transient_retries = build_info.transient_retries.unwrap_or(3);
 
     // get the current time (to calculate the elapsed time after build finishes)
     let started = Instant::now();
+    lockfile.deadline = arguments.max_time
+        .map(|seconds| started + Duration::from_secs(seconds));
+
+    lockfile.jobs = arguments.jobs.unwrap_or_else(available_jobs);
+    lockfile.max_inflight_output = arguments.max_inflight_output
+        .map(|slots| slots as usize);
+
+    for (target, seconds) in &arguments.target_timeout {
+        build_info.find_executable(target, "target-timeout");
+        lockfile.target_timeouts.insert(target.clone(), *seconds);
+    }
+
+    if arguments.sorted {
+        build_info.executables.sort_by(|a, b| a.target.cmp(&b.target));
+    }
+
+    // dependencies are built first, so `--sorted` only breaks ties
+    build_info.order_by_depends();
+
+    // if a target is marked as the entry (or was picked interactively), only
+    // that target (and the targets it depends on) gets built
+    if let Some(selected) = &selected {
+        let targets: HashSet<String> = selected
+            .iter()
+            .flat_map(|target| build_info.with_depends(target))
+            .collect();
+        build_info.executables.retain(|exec| targets.contains(&exec.target));
+    }
+
+    // targets whose `if` expression is false are skipped entirely
+    build_info.executables.retain(|exec| {
+        let enabled = match &exec.if_expression {
+            Some(expression) => check_expression(
+                expression,
+                &exec.resolved_variables,
+                format!("target '{}'", exec.target).as_str()
+            ),
+            None => true
+        };
+
+        if !enabled && !arguments.only_enabled {
+            say!("{} '{}' (if condition is false)",
+                style("Skipping target").yellow(),
+                exec.target
+            );
+        }
+
+        enabled
+    });
+
+    if build_info.executables.iter().any(|exec| exec.parallel == Some(true)) {
+        say!("[coyote] Running up to {} command(s) of each parallel target at \
+            once", lockfile.jobs);
+    }
+
+    check_requirements(build_info.requires.as_deref().unwrap_or_default());
+    if !arguments.skip_program_check {
+        lockfile.check_programs(&build_info.executables);
+    }
+    if !arguments.dry_run {
+        build_info.generate_header();
+    }
 
     // loop through all of the executables and build them in order
-    let mut exec_index = 1;
-    for executable in &build_info.executables {
-        println!("[{}/{}] {} '{}'",
-            exec_index,
+    let mut stopped = false;
+    let mut results: Vec<TargetResult> = Vec::new();
+
+    // with `--keep-going`, the targets that failed or were skipped because a
+    // target they depend on failed
+    let mut failed_targets: HashSet<&str> = HashSet::new();
+    let mut skipped_targets: Vec<&str> = Vec::new();
+    for (exec_index, executable) in build_info.executables.iter().enumerate() {
+        let blocked_by = executable.depends
+            .iter()
+            .flatten()
+            .find(|target| failed_targets.contains(target.as_str()));
+        if let Some(dependency) = blocked_by {
+            say!("{} '{}' (depends on failed target '{}')",
+                style("Skipping target").yellow(),
+                executable.target,
+                dependency
+            );
+            failed_targets.insert(&executable.target);
+            skipped_targets.push(&executable.target);
+            continue;
+        }
+
+        say!("[{}/{}] {} '{}'",
+            exec_index + 1,
             build_info.executables.len(),
             style("Building target").cyan(),
             executable.target
         );
 
-        executable.build(&mut lockfile);
+        if arguments.dry_run {
+            executable.dry_run(&mut lockfile);
+            continue;
+        }
+
+        let result = executable.build(&mut lockfile);
+
+        if let Some(dir) = &arguments.result_dir {
+            write_target_result(dir, &result);
+        }
+
+        let outcome = result.outcome;
+        results.push(result);
+
+        match outcome {
+            BuildOutcome::Finished => {},
+            BuildOutcome::Failed if arguments.keep_going => {
+                failed_targets.insert(&executable.target);
+            },
+            BuildOutcome::Stopped => {
+                stopped = true;
+                break;
+            },
+            BuildOutcome::TimedOut => {
+                // the build is incomplete, so coyote.LOCK is left as it was.
+                // If the build's own deadline hasn't passed, it was the
+                // target's timeout that ran out
+                let build_timed_out = lockfile.deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline);
+                match lockfile.target_timeouts.get(&executable.target) {
+                    Some(seconds) if !build_timed_out => print_error(format!(
+                        "Target '{}' timed out after {} second(s)",
+                        executable.target, seconds).as_str(), true,
+                        "target-timeout"),
+                    _ => print_error(format!("Build of project '{}' timed out \
+                        after {} second(s)", build_info.project_name,
+                        arguments.max_time.unwrap_or_default()).as_str(), true,
+                        "max-time")
+                }
+                write_reports(&arguments, &build_info.project_name, outcome,
+                    started, &results);
+
+                if arguments.stats {
+                    print_stats(&results, &lockfile.durations);
+                }
+                process::exit(-1);
+            },
+            BuildOutcome::Failed => {
+                // remember which commands failed, but throw away the
+                // timestamps recorded during this build so that everything
+                // gets checked again next time
+                lockfile.last_modified = original_modified;
+                lockfile.last_hash = original_hash;
+                write_lock(&lockfile);
+                write_reports(&arguments, &build_info.project_name, outcome,
+                    started, &results);
+
+                if arguments.stats {
+                    print_stats(&results, &lockfile.durations);
+                }
+                process::exit(failure_exit_code(&results));
+            }
+        }
+    }
+
+    // a `--keep-going` build fails once everything that could be built has
+    // been, in the same way as any other failed build
+    if !failed_targets.is_empty() {
+        lockfile.last_modified = original_modified;
+        lockfile.last_hash = original_hash;
+        write_lock(&lockfile);
+
+        print_failures(&results, &skipped_targets);
+        write_reports(&arguments, &build_info.project_name,
+            BuildOutcome::Failed, started, &results);
+
+        if arguments.stats {
+            print_stats(&results, &lockfile.durations);
+        }
+        process::exit(failure_exit_code(&results));
+    }
 
-        exec_index += 1;
+    // conditions may have recorded new timestamps, which must not be kept
+    if arguments.dry_run {
+        say!("{}", style(format!("[coyote] Dry run of project '{}' finished, \
+            nothing was run", build_info.project_name)).green());
+        return;
     }
 
+    // the build completed, so there is nothing left to resume
+    lockfile.completed.clear();
+
     // overwrite coyote.LOCK
-    if let Ok(lock_json) = serde_json::to_string(&lockfile) {
-        fs::write("./coyote.LOCK", lock_json).expect("Uh oh");
+    write_lock(&lockfile);
+
+    if stopped {
+        say_always!("{}", style(format!(
+            "[coyote] Build of project '{}' stopped early after {}",
+            build_info.project_name,
+            HumanDuration(started.elapsed()))).yellow());
+    } else {
+        say_always!("{}", style(format!(
+            "[coyote] Finished building project '{}' in {}",
+            build_info.project_name,
+            HumanDuration(started.elapsed()))).green());
     }
-    else {
-        format_error("Failed to convert coyote.LOCK into JSON format.",
-            true,
-            ""
-        );
+
+    let outcome = if stopped {
+        BuildOutcome::Stopped
+    } else {
+        BuildOutcome::Finished
+    };
+    write_reports(&arguments, &build_info.project_name, outcome, started,
+        &results);
+
+    if arguments.stats {
+        print_stats(&results, &lockfile.durations);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols_use_emoji_when_supported() {
+        assert_eq!(pick_symbol(&GREEN_TICK, false, true), "✅");
+        assert_eq!(pick_symbol(&STOP_SIGN, false, true), "🛑");
+    }
+
+    #[test]
+    fn symbols_fall_back_without_emoji_support() {
+        assert_eq!(pick_symbol(&GREEN_TICK, false, false), "[+]");
+        assert_eq!(pick_symbol(&RED_CROSS, false, false), "[x]");
+        assert_eq!(pick_symbol(&STOP_SIGN, false, false), "[-]");
+    }
+
+    #[test]
+    fn ascii_flag_forces_fallbacks() {
+        assert_eq!(pick_symbol(&GREEN_TICK, true, true), "[+]");
+        assert_eq!(pick_symbol(&RED_CROSS, true, true), "[x]");
+    }
+
+    #[test]
+    fn fallbacks_are_ascii() {
+        for emoji in [&GREEN_TICK, &RED_CROSS, &STOP_SIGN] {
+            assert!(!emoji.1.is_empty() && emoji.1.is_ascii());
+        }
+    }
+
+    #[test]
+    fn global_env_overrides_inherited_environment() {
+        let command: Command = serde_json::from_str(
+            r#"{ "command": "true", "arguments": [] }"#).unwrap();
+        let global = BTreeMap::from([
+            ("PATH".to_string(), "/opt/toolchain/bin".to_string()),
+            ("COYOTE_TEST_CC".to_string(), "clang".to_string())
+        ]);
+
+        let environment = command.environment(&global);
+        let lookup = |key: &str| environment
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str());
+
+        assert_eq!(lookup("PATH"), Some("/opt/toolchain/bin"));
+        assert_eq!(lookup("COYOTE_TEST_CC"), Some("clang"));
+    }
+
+    #[test]
+    fn command_env_overrides_global_env() {
+        let command: Command = serde_json::from_str(r#"{
+            "command": "cc",
+            "arguments": [],
+            "env": { "CFLAGS": "-O0 -g" }
+        }"#).unwrap();
+        let global = BTreeMap::from([
+            ("CFLAGS".to_string(), "-O2".to_string()),
+            ("CC".to_string(), "clang".to_string())
+        ]);
+
+        let environment = command.environment(&global);
+        let lookup = |key: &str| environment
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str());
+
+        assert_eq!(lookup("CFLAGS"), Some("-O0 -g"));
+        assert_eq!(lookup("CC"), Some("clang"));
+    }
+
+    #[test]
+    fn environment_is_inherited_without_global_env() {
+        let command: Command = serde_json::from_str(
+            r#"{ "command": "true", "arguments": [] }"#).unwrap();
+        let inherited = std::env::vars_os().count();
+
+        assert_eq!(command.environment(&BTreeMap::new()).len(), inherited);
+    }
+
+    #[test]
+    fn fixed_backoff_keeps_the_base_delay() {
+        for retry in 1..5 {
+            assert_eq!(Backoff::Fixed.delay(250, retry),
+                Duration::from_millis(250));
+        }
+    }
+
+    #[test]
+    fn linear_backoff_grows_with_each_retry() {
+        assert_eq!(Backoff::Linear.delay(100, 1), Duration::from_millis(100));
+        assert_eq!(Backoff::Linear.delay(100, 2), Duration::from_millis(200));
+        assert_eq!(Backoff::Linear.delay(100, 5), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_each_retry() {
+        assert_eq!(Backoff::Exponential.delay(100, 1),
+            Duration::from_millis(100));
+        assert_eq!(Backoff::Exponential.delay(100, 2),
+            Duration::from_millis(200));
+        assert_eq!(Backoff::Exponential.delay(100, 4),
+            Duration::from_millis(800));
+    }
+
+    #[test]
+    fn exponential_backoff_saturates() {
+        assert_eq!(Backoff::Exponential.delay(1000, 200),
+            Duration::from_millis(u64::MAX));
+    }
+
+    #[test]
+    fn retry_policy_defaults_to_no_delay() {
+        let policy: RetryPolicy =
+            serde_json::from_str(r#"{ "count": 3 }"#).unwrap();
+        assert_eq!(policy.delay(3), Duration::ZERO);
+    }
+
+    #[test]
+    fn command_retry_policy_overrides_target() {
+        let executable: Executable = serde_json::from_str(r#"{
+            "target": "flaky",
+            "retry": { "count": 2, "delay_ms": 10 },
+            "commands": [
+                { "command": "a", "arguments": [] },
+                {
+                    "command": "b",
+                    "arguments": [],
+                    "retry": { "count": 5, "backoff": "linear" }
+                }
+            ]
+        }"#).unwrap();
+
+        let first = executable.retry_policy(&executable.commands[0]).unwrap();
+        let second = executable.retry_policy(&executable.commands[1]).unwrap();
+        assert_eq!(first.count, 2);
+        assert_eq!(second.count, 5);
+        assert_eq!(second.backoff, Some(Backoff::Linear));
+    }
+
+    #[test]
+    fn doubled_backticks_are_literal() {
+        let patched = patch_string("``{name}`` says `echo hi` (``sh``)",
+            &HashMap::from([("name".to_string(), "coyote".to_string())]),
+            None).unwrap();
+        assert_eq!(patched, "`coyote` says hi (`sh`)");
+    }
+
+    #[test]
+    fn run_if_reports_undefined_reference() {
+        let mut command: Command = serde_json::from_str(r#"{
+            "command": "cc",
+            "arguments": [],
+            "run_if": [ "all", [ "modified", "{src}" ], [ "exists", "{out}" ] ]
+        }"#).unwrap();
+        let variables = HashMap::from([
+            ("src".to_string(), "main.c".to_string())
+        ]);
+
+        assert_eq!(command.patch_run_if(&variables, None),
+            Err("out".to_string()));
+    }
+
+    #[test]
+    fn clean_refuses_to_delete_the_project_directory() {
+        for path in [".", "./", "", "..", "src/../..", "/tmp"] {
+            assert!(removable_output(path).is_err(), "{:?}", path);
+        }
+        assert!(removable_output("./build/out.o").is_ok());
+    }
+
+    #[test]
+    fn bare_list_of_conditions_means_all() {
+        let parse = |json: &str| -> Vec<ConditionToken> {
+            serde_json::from_str(json).unwrap()
+        };
+        let mut lock = CoyoteLock::new();
+        let state = BuildState { previous_ran: false };
+
+        // `exists` is met when the file is missing, so that it gets created
+        let met = parse(r#"[["exists", "nope"], ["exists", "nope.o"]]"#);
+        assert!(run_if_met(&met, "t", &mut lock, &state));
+
+        let unmet = parse(r#"[["exists", "nope"], ["exists", "Cargo.toml"]]"#);
+        assert!(!run_if_met(&unmet, "t", &mut lock, &state));
+    }
+
+    #[test]
+    fn deferred_references_are_shown_without_escapes() {
+        let variables = HashMap::from([("a".to_string(), "{1}".to_string())]);
+        let deferred = HashSet::from(["X".to_string()]);
+
+        let escaped = patch_variable_references("{{b} {a} {X}", &variables,
+            Some(&deferred)).unwrap();
+        assert_eq!(unescape_braces(&escaped), "{b} {1} {X}");
+    }
+
+    #[test]
+    fn formatting_round_trips_quotes_backslashes_and_unicode() {
+        let config = serde_json::json!({
+            "project_name": "say \"hi\"",
+            "variables": { "dir": "C:\\build\\", "greeting": "héllo 🐺" },
+            "executables": [{
+                "target": "quote\"d \\ tärget",
+                "commands": [{
+                    "command": "echo",
+                    "arguments": ["it's", "back\\slash", "\"", "日本語"]
+                }]
+            }]
+        });
+
+        for path in ["coyote.json", "coyote.yaml", "coyote.toml"] {
+            let parsed = parse_config(&config.to_string(), "coyote.json");
+            let formatted = serialize_config(&parsed, path).unwrap();
+            let reparsed = parse_config(&formatted, path);
+
+            assert_eq!(serde_json::to_value(&reparsed).unwrap(), config,
+                "{}", path);
+        }
+    }
+
+    #[test]
+    fn exists_accepts_glob_patterns() {
+        let mut lock = CoyoteLock::new();
+        let state = BuildState { previous_ran: false };
+        let mut exists = |path: &str| condition_met(
+            &["exists".to_string(), path.to_string()], "t".to_string(),
+            &mut lock, &state);
+
+        assert!(!exists("src/*.rs"));
+        assert!(exists("src/*.nope"));
+
+        let mut not_exists = |path: &str| condition_met(
+            &["not_exists".to_string(), path.to_string()], "t".to_string(),
+            &mut lock, &state);
+        assert!(not_exists("Cargo.toml"));
+        assert!(not_exists("src/*.rs"));
+        assert!(!not_exists("src/*.nope"));
+    }
+
+    #[test]
+    fn glob_and_modified_keep_separate_timestamps() {
+        let mut lock = CoyoteLock::new();
+        let state = BuildState { previous_ran: false };
+        let mut met = |words: &[&str]| condition_met(
+            &words.iter().map(|word| word.to_string()).collect::<Vec<_>>(),
+            "t".to_string(), &mut lock, &state);
+
+        assert!(met(&["glob", "Cargo.tom?"]));
+        assert!(!met(&["glob", "Cargo.tom?"]));
+
+        // the glob has seen Cargo.toml, but `modified` hasn't yet
+        assert!(met(&["modified", "Cargo.toml"]));
+        assert!(!met(&["modified", "Cargo.toml"]));
+    }
+
+    #[test]
+    fn command_line_quotes_arguments() {
+        let command: Command = serde_json::from_value(serde_json::json!({
+            "command": "grep -c",
+            "arguments": ["two words", "it's", "plain"]
+        })).unwrap();
+
+        assert_eq!(command.command_line(),
+            r#"grep -c 'two words' "it's" plain"#);
+    }
+
+    #[test]
+    fn overridden_variables_are_literal() {
+        let mut config = parse_config(r#"{
+            "project_name": "p",
+            "variables": { "a": "1" },
+            "executables": []
+        }"#, "coyote.json");
+        let value = "`touch ran` {a} {{".to_string();
+        config.override_variables(&[("x".to_string(), value.clone())]);
+        config.preprocess();
+
+        assert_eq!(config.resolved_variables.get("x"), Some(&value));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn programs_are_resolved_on_the_overridden_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tools = std::env::temp_dir()
+            .join(format!("coyote-tools-{}", process::id()));
+        fs::create_dir_all(&tools).unwrap();
+        let tool = tools.join("coyote-test-tool");
+        fs::write(&tool, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut command: Command = serde_json::from_value(serde_json::json!({
+            "command": "coyote-test-tool",
+            "arguments": []
+        })).unwrap();
+        let mut lock = CoyoteLock::new();
+        lock.resolve_programs = true;
+        assert!(lock.resolve_command(&command).is_ok_and(|path| path != tool));
+
+        // the top level `env`, and then the command's own, decide the PATH
+        let path = tools.to_string_lossy().into_owned();
+        lock.env.insert("PATH".to_string(), path.clone());
+        assert_eq!(lock.resolve_command(&command), Ok(tool.clone()));
+
+        lock.env.insert("PATH".to_string(), "/nonexistent".to_string());
+        command.env = Some(BTreeMap::from([("PATH".to_string(), path)]));
+        assert_eq!(lock.resolve_command(&command), Ok(tool));
+
+        fs::remove_dir_all(&tools).unwrap();
+    }
+
+    #[test]
+    fn missing_programs_are_found_without_resolving() {
+        let mut lock = CoyoteLock::new();
+        assert_eq!(lock.resolve_program("sh", None), Some(PathBuf::from("sh")));
+        assert!(lock.find_program("sh", None).is_some_and(|path| path
+            .is_absolute()));
+        assert_eq!(lock.find_program("coyote-no-such-program", None), None);
+        assert_eq!(lock.resolve_program("coyote-no-such-program", None), None);
+    }
+
+    #[test]
+    fn streamed_lines_are_filtered() {
+        let filter = LineFilter {
+            include: Some(Regex::new("^warning").unwrap()),
+            exclude: Some(Regex::new("unused").unwrap())
+        };
+        assert!(filter.shows("warning: field is never read"));
+        assert!(!filter.shows("warning: unused import"));
+        assert!(!filter.shows("   Compiling coyote-build"));
+
+        let filter = LineFilter { include: None, exclude: None };
+        assert!(filter.shows("   Compiling coyote-build"));
+    }
+
+    #[test]
+    fn streamed_output_waits_for_a_slot() {
+        let slots = Some(Arc::new(Mutex::new(1)));
+        let filter = LineFilter { include: None, exclude: None };
+        let first = LiveOutput::new(ProgressBar::hidden(), filter.clone(),
+            slots.clone());
+        let second = LiveOutput::new(ProgressBar::hidden(), filter, slots);
+
+        first.print("first", false);
+        second.print("second", false);
+        assert!(first.state.lock().unwrap().live);
+        assert_eq!(second.state.lock().unwrap().pending.len(), 1);
+
+        // the held back line is printed once the slot is given up
+        first.finish();
+        second.print("second again", false);
+        assert!(second.state.lock().unwrap().live);
+        assert!(second.state.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn plan_dot_draws_depends_and_outputs() {
+        let config = parse_config(r#"{
+            "project_name": "p",
+            "variables": {},
+            "executables": [
+                { "target": "gen", "commands": [
+                    { "command": "gen", "arguments": [],
+                        "outputs": ["out.h"] }
+                ] },
+                { "target": "build", "depends": ["gen"], "commands": [
+                    { "command": "cc", "arguments": ["a b.c"],
+                        "stdin_file": "out.h" },
+                    { "command": "ld", "arguments": [] }
+                ] }
+            ]
+        }"#, "coyote.json");
+        let dot = config.plan_dot();
+
+        assert!(dot.contains(r#""build:0" [label="cc 'a b.c'"];"#));
+        assert!(dot.contains(r#""build:0" -> "build:1";"#));
+        assert!(dot.contains(r#""gen:0" -> "build:0" [ltail=cluster_0"#));
+        assert!(dot.contains(
+            r#""gen:0" -> "build:0" [style=dashed, label="out.h"];"#));
     }
 
-    println!("{}", style(format!(
-        "[coyote] Finished building project '{}' in {}",
-        build_info.project_name,
-        HumanDuration(started.elapsed()))).green());
+    #[test]
+    fn glob_patterns_are_watched_from_their_base() {
+        assert_eq!(glob_base("src/**/*.rs"), ("src", "**/*.rs"));
+        assert_eq!(glob_base("*.c"), (".", "*.c"));
+        assert_eq!(glob_base("/usr/include/*.h"), ("/usr/include", "*.h"));
+        assert_eq!(glob_base("/*"), ("/", "*"));
+        assert_eq!(glob_base("assets/logo.png"), ("assets", "logo.png"));
+    }
+
+    #[test]
+    fn extends_merges_the_base_target() {
+        let mut config = parse_config(r#"{
+            "project_name": "p",
+            "variables": {},
+            "executables": [
+                { "target": "setup", "commands": [] },
+                { "target": "base", "depends": ["setup"], "parallel": true,
+                    "container": "alpine", "variables": { "a": "1", "b": "1" },
+                    "on_failure": [{ "command": "cleanup", "arguments": [] }],
+                    "commands": [{ "command": "mkdir", "arguments": [] }] },
+                { "target": "debug", "extends": "base", "parallel": false,
+                    "variables": { "b": "2" },
+                    "commands": [{ "command": "gcc", "arguments": [] }] }
+            ]
+        }"#, "coyote.json");
+        config.resolve_extends();
+        let debug = &config.executables[2];
+
+        let commands: Vec<&str> = debug.commands
+            .iter()
+            .map(|command| command.command.as_str())
+            .collect();
+        assert_eq!(commands, ["mkdir", "gcc"]);
+        assert_eq!(debug.depends, Some(vec!["setup".to_string()]));
+        assert_eq!(debug.on_failure.as_ref().map(Vec::len), Some(1));
+        assert_eq!(debug.container.as_deref(), Some("alpine"));
+        assert_eq!(debug.parallel, Some(false));
+        assert_eq!(debug.variables, Some(serde_json::json!({
+            "a": "1", "b": "2"
+        })));
+    }
 }